@@ -0,0 +1,78 @@
+
+use crate::{Error, KeyValue};
+
+#[derive(Debug, Clone)]
+pub enum BatchOp {
+    Put(u64, Vec<u8>, Vec<u8>),
+    Delete(u64, Vec<u8>),
+    /// Value the key must hold, `None` for absent
+    Expect(u64, Vec<u8>, Option<Vec<u8>>),
+}
+
+/// Puts and deletes applied all-or-nothing by `KeyValue::write`, in the
+/// order they were added. Expectations are checked before anything is
+/// applied, the batch fails with `Error::Conflict` if one does not hold.
+#[derive(Debug, Default, Clone)]
+pub struct WriteBatch {
+    pub ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, ver: u64, key: &[u8], val: &[u8]) {
+        self.ops.push(BatchOp::Put(ver, key.to_owned(), val.to_owned()));
+    }
+
+    pub fn put_str(&mut self, ver: u64, key: &str, val: &[u8]) {
+        self.put(ver, key.as_bytes(), val)
+    }
+
+    pub fn delete(&mut self, ver: u64, key: &[u8]) {
+        self.ops.push(BatchOp::Delete(ver, key.to_owned()));
+    }
+
+    pub fn expect(&mut self, ver: u64, key: &[u8], val: Option<&[u8]>) {
+        self.ops.push(BatchOp::Expect(ver, key.to_owned(), val.map(|v| v.to_owned())));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// Checks the expectations of `batch` against `get`, for stores applying
+/// it under their own lock.
+pub (crate) fn check_expected<F>(batch: &WriteBatch, mut get: F) -> Result<(), Error>
+    where F: FnMut(u64, &[u8]) -> Result<Option<Vec<u8>>, Error> {
+
+    for op in batch.ops.iter() {
+        if let BatchOp::Expect(ver, key, val) = op {
+            if get(*ver, key)? != *val {
+                return Err(Error::Conflict { ver: *ver, key: key.clone() });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// For layers storing values transformed: the bytes `kv` holds for an
+/// expected value, so the store below can compare them. `decode` undoes
+/// the layer's transform.
+pub (crate) fn expect_stored<F>(kv: &dyn KeyValue, ver: u64, key: &[u8], val: &[u8], decode: F) -> Result<Vec<u8>, Error>
+    where F: FnOnce(Vec<u8>) -> Result<Vec<u8>, Error> {
+
+    let stored = kv.get(ver, key)?.ok_or(Error::Conflict { ver, key: key.to_owned() })?;
+    if decode(stored.clone())? != val {
+        return Err(Error::Conflict { ver, key: key.to_owned() });
+    }
+
+    Ok(stored)
+}
@@ -0,0 +1,263 @@
+use crate::KeyValue;
+
+use std::sync::Arc;
+use std::hash::Hash;
+use std::collections::{BTreeMap, HashMap};
+use parking_lot::Mutex;
+use crate::{Error, Scan, ScanItems, WriteBatch, BatchOp};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+/// Least recently used cache bounded by the total size of its entries.
+/// Sizes are given by the caller on insert.
+pub struct LruCache<K, V> {
+    capacity: usize,
+    bytes: usize,
+    tick: u64,
+    map: HashMap<K, (V, usize, u64)>,
+    order: BTreeMap<u64, K>,
+    hits: u64,
+    misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> LruCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        LruCache {
+            capacity,
+            bytes: 0,
+            tick: 0,
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        match self.map.get_mut(key) {
+            Some(entry) => {
+                self.order.remove(&entry.2);
+                self.order.insert(tick, key.clone());
+                entry.2 = tick;
+                self.hits += 1;
+                Some(entry.0.clone())
+            },
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Entries larger than the whole capacity are not cached.
+    pub fn insert(&mut self, key: K, val: V, size: usize) {
+        self.remove(&key);
+        if size > self.capacity {
+            return;
+        }
+
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.map.insert(key, (val, size, self.tick));
+        self.bytes += size;
+
+        self.evict();
+    }
+
+    pub fn remove(&mut self, key: &K) {
+        if let Some((_, size, tick)) = self.map.remove(key) {
+            self.order.remove(&tick);
+            self.bytes -= size;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+        self.bytes = 0;
+    }
+
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.map.len(),
+            bytes: self.bytes,
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.bytes > self.capacity {
+            let tick = match self.order.keys().next() {
+                Some(tick) => *tick,
+                None => break,
+            };
+
+            if let Some(key) = self.order.remove(&tick) {
+                if let Some((_, size, _)) = self.map.remove(&key) {
+                    self.bytes -= size;
+                }
+            }
+        }
+    }
+}
+
+type ValueCache = LruCache<(u64, Vec<u8>), Vec<u8>>;
+
+struct CacheInner {
+    values: ValueCache,
+    /// Bumped by every change, a read that missed only caches its value
+    /// when no change went in meanwhile
+    generation: u64,
+}
+
+impl CacheInner {
+    fn invalidate(&mut self, ver: u64, key: &[u8]) {
+        self.generation += 1;
+        self.values.remove(&(ver, key.to_owned()));
+    }
+}
+
+/// Write-through cache of raw values. Misses and version 0, which holds
+/// state other writers change, are not cached. Clones share the cache,
+/// keep one to read the stats.
+///
+/// Changes other processes make below version 0, as squash rewriting the
+/// indexes of kept versions, are not seen. It suits stores with a single
+/// user, vst leaves it out and relies on the pack cache of `VStore`.
+#[derive(Clone)]
+pub struct CacheKV {
+    kv: Arc<Box<dyn KeyValue>>,
+    cache: Arc<Mutex<CacheInner>>,
+}
+
+impl CacheKV {
+    pub fn new(kv: Arc<Box<dyn KeyValue>>, capacity: usize) -> Self {
+        CacheKV {
+            kv,
+            cache: Arc::new(Mutex::new(CacheInner {
+                values: LruCache::new(capacity),
+                generation: 0,
+            })),
+        }
+    }
+
+    pub fn new_box(kv: Arc<Box<dyn KeyValue>>, capacity: usize) -> Box<dyn KeyValue> {
+        Box::new(CacheKV::new(kv, capacity))
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().values.stats()
+    }
+}
+
+impl KeyValue for CacheKV {
+    fn put(&self, ver: u64, key: &[u8], val: &[u8]) -> Result<(), Error> {
+        let mut cache = self.cache.lock();
+        cache.invalidate(ver, key);
+        self.kv.put(ver, key, val)?;
+
+        if ver != 0 {
+            cache.values.insert((ver, key.to_owned()), val.to_owned(), key.len() + val.len());
+        }
+        Ok(())
+    }
+
+    fn get(&self, ver: u64, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        if ver == 0 {
+            return self.kv.get(ver, key);
+        }
+
+        let generation = {
+            let mut cache = self.cache.lock();
+            if let Some(val) = cache.values.get(&(ver, key.to_owned())) {
+                return Ok(Some(val));
+            }
+            cache.generation
+        };
+
+        let val = self.kv.get(ver, key)?;
+
+        if let Some(val) = &val {
+            let mut cache = self.cache.lock();
+            if cache.generation == generation {
+                cache.values.insert((ver, key.to_owned()), val.clone(), key.len() + val.len());
+            }
+        }
+        Ok(val)
+    }
+
+    fn delete(&self, ver: u64, key: &[u8]) -> Result<(), Error> {
+        let mut cache = self.cache.lock();
+        cache.invalidate(ver, key);
+        self.kv.delete(ver, key)
+    }
+
+    fn scan(&self, ver: u64, scan: &Scan) -> Result<ScanItems, Error> {
+        self.kv.scan(ver, scan)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), Error> {
+        // Holding the lock keeps readers from caching values the batch replaces
+        let mut cache = self.cache.lock();
+
+        for op in batch.ops.iter() {
+            match op {
+                BatchOp::Put(ver, key, _) | BatchOp::Delete(ver, key) => {
+                    cache.invalidate(*ver, key);
+                },
+                BatchOp::Expect(..) => {},
+            }
+        }
+
+        self.kv.write(batch)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.kv.sync()
+    }
+
+    fn create_version(&self, ver: u64) -> Result<(), Error> {
+        self.kv.create_version(ver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryKV;
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn sees_writes_below() {
+        let kv: Arc<Box<dyn KeyValue>> = Arc::new(MemoryKV::new_box());
+        let ckv = CacheKV::new(kv.clone(), 1024);
+
+        assert_eq!(ckv.get(1, b"a").unwrap(), None);
+        ckv.get(0, b"state").unwrap();
+
+        kv.put(1, b"a", b"one").unwrap();
+        kv.put(0, b"state", b"two").unwrap();
+
+        assert_eq!(ckv.get(1, b"a").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(ckv.get(0, b"state").unwrap(), Some(b"two".to_vec()));
+
+        ckv.put(1, b"a", b"three").unwrap();
+        assert_eq!(ckv.get(1, b"a").unwrap(), Some(b"three".to_vec()));
+        assert_eq!(ckv.stats().hits, 1);
+    }
+}
@@ -0,0 +1,210 @@
+use crate::KeyValue;
+
+use std::sync::Arc;
+use std::convert::TryInto;
+use xxhash_rust::xxh3::xxh3_64;
+use crate::{Error, Scan, ScanItems, WriteBatch, BatchOp};
+use crate::batch::expect_stored;
+use log::debug;
+
+/// Key of the record at version 0 marking a store whose values all carry
+/// checksums. It is reserved, callers must not use it.
+pub const MARKER_KEY: &[u8] = b"__checksum";
+
+const CHECKSUM_LEN: usize = 8;
+
+/// Appends an xxh3 checksum to every value and verifies it on read,
+/// returning `Error::Corruption` on mismatch.
+pub struct ChecksumKV {
+    kv: Arc<Box<dyn KeyValue>>,
+}
+
+impl ChecksumKV {
+    pub fn new(kv: Arc<Box<dyn KeyValue>>) -> Self {
+        ChecksumKV{
+            kv,
+        }
+    }
+
+    pub fn new_box(kv: Arc<Box<dyn KeyValue>>) -> Box<dyn KeyValue> {
+        Box::new(ChecksumKV::new(kv))
+    }
+
+    /// Marks an empty store as checksummed, it must then only be opened
+    /// through `ChecksumKV`.
+    pub fn create(kv: Arc<Box<dyn KeyValue>>) -> Result<Self, Error> {
+        if !kv.scan(0, &Scan::all().limit(1))?.is_empty() {
+            return Err(Error::ImplError("store has data without checksums".to_owned()));
+        }
+
+        let ckv = ChecksumKV::new(kv);
+        ckv.put(0, MARKER_KEY, b"xxh3")?;
+        ckv.sync()?;
+
+        Ok(ckv)
+    }
+
+    pub fn create_box(kv: Arc<Box<dyn KeyValue>>) -> Result<Box<dyn KeyValue>, Error> {
+        Ok(Box::new(ChecksumKV::create(kv)?))
+    }
+
+    /// True when the store was marked by `create`.
+    pub fn is_checksummed(kv: &dyn KeyValue) -> Result<bool, Error> {
+        Ok(kv.get(0, MARKER_KEY)?.is_some())
+    }
+}
+
+impl KeyValue for ChecksumKV {
+    fn put(&self, ver: u64, key: &[u8], val: &[u8]) -> Result<(), Error> {
+        self.kv.put(ver, key, &seal(val))
+    }
+
+    fn get(&self, ver: u64, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        match self.kv.get(ver, key)? {
+            None => Ok(None),
+            Some(buf) => Ok(Some(verify(ver, key, buf)?)),
+        }
+    }
+
+    fn delete(&self, ver: u64, key: &[u8]) -> Result<(), Error> {
+        self.kv.delete(ver, key)
+    }
+
+    fn scan(&self, ver: u64, scan: &Scan) -> Result<ScanItems, Error> {
+        let mut items = self.kv.scan(ver, scan)?;
+
+        if ver == 0 {
+            items.retain(|(k, _)| k != MARKER_KEY);
+        }
+
+        items.into_iter()
+            .map(|(k, v)| {
+                let v = verify(ver, &k, v)?;
+                Ok((k, v))
+            })
+            .collect()
+    }
+
+    fn write(&self, mut batch: WriteBatch) -> Result<(), Error> {
+        for op in batch.ops.iter_mut() {
+            match op {
+                BatchOp::Put(_, _, val) => *val = seal(val),
+                BatchOp::Expect(ver, key, Some(val)) => {
+                    *val = expect_stored(&**self.kv, *ver, key, val, |buf| verify(*ver, key, buf))?;
+                },
+                _ => {},
+            }
+        }
+
+        self.kv.write(batch)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.kv.sync()
+    }
+
+    fn create_version(&self, ver: u64) -> Result<(), Error> {
+        self.kv.create_version(ver)
+    }
+}
+
+fn seal(val: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(val.len() + CHECKSUM_LEN);
+    buf.extend_from_slice(val);
+    buf.extend_from_slice(&xxh3_64(val).to_be_bytes());
+    buf
+}
+
+fn verify(ver: u64, key: &[u8], mut buf: Vec<u8>) -> Result<Vec<u8>, Error> {
+    if buf.len() < CHECKSUM_LEN {
+        debug!("checksum missing v={} key={:?}", ver, key);
+        return Err(Error::Corruption { ver, key: key.to_owned() });
+    }
+
+    let pos = buf.len() - CHECKSUM_LEN;
+    let sum = u64::from_be_bytes(buf[pos..].try_into().unwrap());
+    buf.truncate(pos);
+
+    if xxh3_64(&buf) != sum {
+        debug!("checksum mismatch v={} key={:?}", ver, key);
+        return Err(Error::Corruption { ver, key: key.to_owned() });
+    }
+
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryKV;
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn checksummed() -> (Arc<Box<dyn KeyValue>>, ChecksumKV) {
+        let kv: Arc<Box<dyn KeyValue>> = Arc::new(MemoryKV::new_box());
+        let ckv = ChecksumKV::new(kv.clone());
+        (kv, ckv)
+    }
+
+    #[test]
+    fn round_trip() {
+        let (_, ckv) = checksummed();
+        ckv.put(1, b"a", b"one").unwrap();
+        ckv.put(1, b"b", b"").unwrap();
+
+        assert_eq!(ckv.get(1, b"a").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(ckv.scan(1, &Scan::all()).unwrap(), vec![
+            (b"a".to_vec(), b"one".to_vec()),
+            (b"b".to_vec(), b"".to_vec()),
+        ]);
+
+        let mut batch = WriteBatch::new();
+        batch.expect(1, b"a", Some(b"one"));
+        batch.put(1, b"a", b"two");
+        ckv.write(batch).unwrap();
+        assert_eq!(ckv.get(1, b"a").unwrap(), Some(b"two".to_vec()));
+
+        let mut batch = WriteBatch::new();
+        batch.expect(1, b"a", Some(b"one"));
+        batch.put(1, b"a", b"three");
+        assert!(matches!(ckv.write(batch), Err(Error::Conflict{..})));
+        assert_eq!(ckv.get(1, b"a").unwrap(), Some(b"two".to_vec()));
+    }
+
+    #[test]
+    fn flipped_byte() {
+        let (kv, ckv) = checksummed();
+        ckv.put(1, b"a", b"one").unwrap();
+
+        let mut buf = kv.get(1, b"a").unwrap().unwrap();
+        buf[1] ^= 1;
+        kv.put(1, b"a", &buf).unwrap();
+
+        match ckv.get(1, b"a") {
+            Err(Error::Corruption { ver, key }) => assert_eq!((ver, key), (1, b"a".to_vec())),
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(matches!(ckv.scan(1, &Scan::all()), Err(Error::Corruption{..})));
+    }
+
+    #[test]
+    fn value_shorter_than_checksum() {
+        let (kv, ckv) = checksummed();
+        kv.put(1, b"a", b"abc").unwrap();
+        kv.put(1, b"b", b"").unwrap();
+
+        assert!(matches!(ckv.get(1, b"a"), Err(Error::Corruption{..})));
+        assert!(matches!(ckv.get(1, b"b"), Err(Error::Corruption{..})));
+    }
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn marker() {
+        let kv: Arc<Box<dyn KeyValue>> = Arc::new(MemoryKV::new_box());
+        assert!(!ChecksumKV::is_checksummed(&**kv).unwrap());
+
+        let ckv = ChecksumKV::create(kv.clone()).unwrap();
+        assert!(ChecksumKV::is_checksummed(&**kv).unwrap());
+        assert!(ckv.scan(0, &Scan::all()).unwrap().is_empty());
+        assert!(ChecksumKV::create(kv).is_err());
+    }
+}
@@ -2,57 +2,310 @@ use crate::KeyValue;
 
 
 use std::sync::Arc;
-use crate::Error;
+use std::convert::TryInto;
+use std::io::Read;
+use parking_lot::Mutex;
+use crate::{Error, Scan, ScanItems, WriteBatch, BatchOp};
+use crate::batch::expect_stored;
 use log::debug;
 
+// Every value starts with one of these tags. Compressed values follow the
+// tag with their uncompressed length as a big endian u64.
+const TAG_RAW: u8 = 0;
+const TAG_ZSTD: u8 = 1;
+const TAG_LZ4: u8 = 2;
+const TAG_ZSTD_DICT: u8 = 3;
+
+/// Values written before tags existed are bare zstd frames.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Largest value written. Longer values are refused by `put` and `write`,
+/// and the length stored with a value is not trusted beyond it.
+pub const MAX_VALUE_LEN: usize = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    None,
+    Zstd,
+    Lz4,
+}
+
+/// Compression settings for new values. Values are tagged with the way
+/// they were written, so the settings can change over the life of a store;
+/// values written with a dictionary need that same dictionary to be read.
+#[derive(Debug, Clone)]
+pub struct CompressOptions {
+    pub algorithm: Algorithm,
+    pub level: i32,
+    pub min_size: usize,
+    pub dictionary: Option<Vec<u8>>,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            algorithm: Algorithm::Zstd,
+            level: 0,
+            min_size: 64,
+            dictionary: None,
+        }
+    }
+}
+
+impl CompressOptions {
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// zstd level, 0 picks the zstd default. Unused by lz4.
+    pub fn level(mut self, level: i32) -> Self {
+        self.level = level;
+        self
+    }
+
+    /// Values shorter than this are stored uncompressed.
+    pub fn min_size(mut self, min_size: usize) -> Self {
+        self.min_size = min_size;
+        self
+    }
+
+    pub fn dictionary(mut self, dictionary: Vec<u8>) -> Self {
+        self.dictionary = Some(dictionary);
+        self
+    }
+}
+
+/// Trains a zstd dictionary of at most `max_size` bytes from sample values.
+pub fn train_dictionary(samples: &[Vec<u8>], max_size: usize) -> Result<Vec<u8>, Error> {
+    Ok(zstd::dict::from_samples(samples, max_size)?)
+}
+
 pub struct CompressKV {
     kv: Arc<Box<dyn KeyValue>>,
+    opts: CompressOptions,
+    compressor: Option<Mutex<zstd::block::Compressor>>,
+    decompressor: Option<Mutex<zstd::block::Decompressor>>,
 }
 
 impl CompressKV {
     pub fn new(kv: Arc<Box<dyn KeyValue>>) -> Box<dyn KeyValue> {
+        CompressKV::new_with(kv, CompressOptions::default())
+    }
+
+    pub fn new_with(kv: Arc<Box<dyn KeyValue>>, opts: CompressOptions) -> Box<dyn KeyValue> {
+        let compressor = opts.dictionary.as_ref()
+            .map(|d| Mutex::new(zstd::block::Compressor::with_dict(d.clone())));
+        let decompressor = opts.dictionary.as_ref()
+            .map(|d| Mutex::new(zstd::block::Decompressor::with_dict(d.clone())));
+
         Box::new(CompressKV{
             kv,
+            opts,
+            compressor,
+            decompressor,
         })
     }
+
+    fn compress(&self, val: &[u8]) -> Result<Vec<u8>, Error> {
+        if val.len() > MAX_VALUE_LEN {
+            return Err(Error::ImplError(format!("value length {} over limit", val.len())));
+        }
+
+        if val.len() < self.opts.min_size {
+            return Ok(tagged_raw(val));
+        }
+
+        let (tag, cbuf) = match (self.opts.algorithm, &self.compressor) {
+            (Algorithm::None, _) => return Ok(tagged_raw(val)),
+            (Algorithm::Zstd, Some(c)) => (TAG_ZSTD_DICT, c.lock().compress(val, self.opts.level)?),
+            (Algorithm::Zstd, None) => (TAG_ZSTD, zstd::block::compress(val, self.opts.level)?),
+            (Algorithm::Lz4, _) => (TAG_LZ4, lz4_flex::block::compress(val)),
+        };
+
+        // Not worth the header when it does not shrink
+        if cbuf.len() + 8 >= val.len() {
+            return Ok(tagged_raw(val));
+        }
+
+        let mut buf = Vec::with_capacity(cbuf.len() + 9);
+        buf.push(tag);
+        buf.extend_from_slice(&(val.len() as u64).to_be_bytes());
+        buf.extend_from_slice(&cbuf);
+        Ok(buf)
+    }
+
+    fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>, Error> {
+        if buf.starts_with(&ZSTD_MAGIC) {
+            let mut val = Vec::new();
+            zstd::stream::read::Decoder::new(buf)?
+                .take(MAX_VALUE_LEN as u64 + 1)
+                .read_to_end(&mut val)?;
+
+            if val.len() > MAX_VALUE_LEN {
+                return Err(Error::ImplError("compressed value over size limit".to_owned()));
+            }
+            return Ok(val);
+        }
+
+        match buf.first() {
+            Some(&TAG_RAW) => return Ok(buf[1..].to_vec()),
+            Some(_) if buf.len() >= 9 => {},
+            _ => return Err(Error::ImplError("invalid compressed value".to_owned())),
+        }
+
+        let len = u64::from_be_bytes(buf[1..9].try_into().unwrap());
+        if len > MAX_VALUE_LEN as u64 {
+            return Err(Error::ImplError(format!("compressed value length {} over limit", len)));
+        }
+
+        let len = len as usize;
+        let data = &buf[9..];
+
+        match buf[0] {
+            TAG_ZSTD => Ok(zstd::block::decompress(data, len)?),
+            TAG_ZSTD_DICT => {
+                let d = self.decompressor.as_ref()
+                    .ok_or(Error::ImplError("zstd dictionary required".to_owned()))?;
+                Ok(d.lock().decompress(data, len)?)
+            },
+            TAG_LZ4 => {
+                lz4_flex::block::decompress(data, len).map_err(|e| Error::ImplError(e.to_string()))
+            },
+            tag => Err(Error::ImplError(format!("unknown compression tag {}", tag))),
+        }
+    }
 }
 
 impl KeyValue for CompressKV {
-    fn put(&self, ver: u16, key: &[u8], val: &[u8]) -> Result<(), Error> {
+    fn put(&self, ver: u64, key: &[u8], val: &[u8]) -> Result<(), Error> {
         debug!("compress put {} {} {}", ver, key.len(), val.len());
 
-        let cbuf = zstd::block::compress(&val, 0)?;
+        let cbuf = self.compress(val)?;
         self.kv.put(ver, key, &cbuf)?;
 
         Ok(())
     }
 
-    fn get(&self, ver: u16, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    fn get(&self, ver: u64, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
         debug!("compress get {} {}", ver, key.len());
 
         match self.kv.get(ver, key)? {
             None=>Ok(None),
             Some(buf) => {
-                Ok(Some(zstd::block::decompress(&buf, 1024 * 1024 * 10)?))
+                Ok(Some(self.decompress(&buf)?))
             }
         }
     }
 
-    fn delete(&self, ver: u16, key: &[u8]) -> Result<(), Error> {
+    fn scan(&self, ver: u64, scan: &Scan) -> Result<ScanItems, Error> {
+        debug!("compress scan {} {}", ver, scan.start.len());
+
+        let mut items = self.kv.scan(ver, scan)?;
+        for item in items.iter_mut() {
+            item.1 = self.decompress(&item.1)?;
+        }
+
+        Ok(items)
+    }
+
+    fn delete(&self, ver: u64, key: &[u8]) -> Result<(), Error> {
         self.kv.delete(ver, key)?;
         Ok(())
     }
 
+    fn write(&self, mut batch: WriteBatch) -> Result<(), Error> {
+        debug!("compress write {}", batch.len());
+
+        for op in batch.ops.iter_mut() {
+            match op {
+                BatchOp::Put(_, _, val) => *val = self.compress(val)?,
+                BatchOp::Expect(ver, key, Some(val)) => {
+                    *val = expect_stored(&**self.kv, *ver, key, val, |buf| self.decompress(&buf))?;
+                },
+                _ => {},
+            }
+        }
+
+        self.kv.write(batch)
+    }
+
     fn sync(&self) -> Result<(), Error> {
         self.kv.sync()
     }
+
+    fn create_version(&self, ver: u64) -> Result<(), Error> {
+        self.kv.create_version(ver)
+    }
+}
+
+fn tagged_raw(val: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(val.len() + 1);
+    buf.push(TAG_RAW);
+    buf.extend_from_slice(val);
+    buf
 }
 
-pub fn make_key(ver: u16, key: &[u8]) -> Vec<u8> {
+pub fn make_key(ver: u64, key: &[u8]) -> Vec<u8> {
     let mut buf: Vec<u8> = Vec::new();
 
     buf.extend_from_slice(&ver.to_be_bytes()[..]);
     buf.push(58);
     buf.extend_from_slice(key);
     buf
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryKV;
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn compressed(opts: CompressOptions) -> (Arc<Box<dyn KeyValue>>, Box<dyn KeyValue>) {
+        let kv: Arc<Box<dyn KeyValue>> = Arc::new(MemoryKV::new_box());
+        let ckv = CompressKV::new_with(kv.clone(), opts);
+        (kv, ckv)
+    }
+
+    #[test]
+    fn round_trip() {
+        let val = b"abcd".repeat(100);
+
+        for alg in &[Algorithm::None, Algorithm::Zstd, Algorithm::Lz4] {
+            let (kv, ckv) = compressed(CompressOptions::default().algorithm(*alg));
+            ckv.put(1, b"k", &val).unwrap();
+
+            assert_eq!(ckv.get(1, b"k").unwrap(), Some(val.clone()));
+            if *alg != Algorithm::None {
+                assert!(kv.get(1, b"k").unwrap().unwrap().len() < val.len());
+            }
+        }
+    }
+
+    #[test]
+    fn length_over_limit() {
+        let (kv, ckv) = compressed(CompressOptions::default());
+
+        let mut buf = vec![TAG_LZ4];
+        buf.extend_from_slice(&u64::MAX.to_be_bytes());
+        buf.extend_from_slice(b"data");
+        kv.put(1, b"k", &buf).unwrap();
+
+        assert!(ckv.get(1, b"k").is_err());
+    }
+
+    #[test]
+    fn value_over_limit() {
+        let (kv, ckv) = compressed(CompressOptions::default());
+        let val = vec![0u8; MAX_VALUE_LEN + 1];
+
+        assert!(ckv.put(1, b"k", &val).is_err());
+
+        // Moved in, the batch would copy it otherwise
+        let mut batch = WriteBatch::new();
+        batch.ops.push(BatchOp::Put(1, b"k".to_vec(), val));
+        assert!(ckv.write(batch).is_err());
+
+        assert_eq!(kv.get(1, b"k").unwrap(), None);
+    }
+}
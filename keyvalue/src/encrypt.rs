@@ -0,0 +1,390 @@
+use crate::KeyValue;
+
+use std::sync::Arc;
+use std::convert::TryInto;
+use chacha20poly1305::{XChaCha20Poly1305, Key, XNonce};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use argon2::{Argon2, Algorithm, Params, Version};
+use crate::{Error, Scan, ScanItems, WriteBatch, BatchOp};
+use crate::batch::expect_stored;
+use log::debug;
+
+/// Key of the header record at version 0. It is stored in the clear and
+/// is reserved, callers must not use it.
+pub const HEADER_KEY: &[u8] = b"__encrypt_header";
+
+const HEADER_MAGIC: &[u8] = b"TAPENC1";
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+
+// Largest cost accepted from a header, 1 GiB of memory
+const MAX_M_COST: u32 = 1024 * 1024;
+const MAX_T_COST: u32 = 64;
+const MAX_P_COST: u32 = 16;
+
+/// Known plaintext sealed in the header to detect a wrong passphrase.
+const CHECK_PLAINTEXT: &[u8] = b"taproot";
+
+/// Encrypts values with XChaCha20-Poly1305 under a key derived from a
+/// passphrase. Keys are not encrypted.
+///
+/// Every value gets a random nonce and is bound to its version and key as
+/// associated data, so a value copied to another key or version fails to
+/// decrypt.
+pub struct EncryptKV {
+    kv: Arc<Box<dyn KeyValue>>,
+    cipher: XChaCha20Poly1305,
+}
+
+/// Argon2id cost of deriving the key, recorded in the header of a new
+/// store. Existing stores are opened with the cost in their header.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    /// Memory in KiB
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        KdfParams {
+            m_cost: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    // A crafted header must not make opening the store exhaust memory or time
+    fn check(&self) -> Result<(), Error> {
+        if self.m_cost > MAX_M_COST || self.t_cost > MAX_T_COST || self.p_cost > MAX_P_COST {
+            return Err(Error::EncryptionError(format!("encryption cost out of range m={} t={} p={}",
+                self.m_cost, self.t_cost, self.p_cost)));
+        }
+
+        Ok(())
+    }
+}
+
+struct Header {
+    kdf: KdfParams,
+    salt: Vec<u8>,
+    check: Vec<u8>,
+}
+
+impl Header {
+    fn to_vec(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+
+        buf.extend_from_slice(HEADER_MAGIC);
+        buf.extend_from_slice(&self.kdf.m_cost.to_be_bytes());
+        buf.extend_from_slice(&self.kdf.t_cost.to_be_bytes());
+        buf.extend_from_slice(&self.kdf.p_cost.to_be_bytes());
+        buf.extend_from_slice(&self.salt);
+        buf.extend_from_slice(&self.check);
+        buf
+    }
+
+    fn from_buf(buf: &[u8]) -> Result<Header, Error> {
+        let fixed_len = HEADER_MAGIC.len() + 12 + SALT_LEN;
+        if buf.len() <= fixed_len || !buf.starts_with(HEADER_MAGIC) {
+            return Err(Error::EncryptionError("invalid encryption header".to_owned()));
+        }
+
+        let cost = |pos: usize| u32::from_be_bytes(buf[pos..pos+4].try_into().unwrap());
+        let pos = HEADER_MAGIC.len();
+
+        let header = Header {
+            kdf: KdfParams {
+                m_cost: cost(pos),
+                t_cost: cost(pos + 4),
+                p_cost: cost(pos + 8),
+            },
+            salt: buf[pos+12..fixed_len].to_vec(),
+            check: buf[fixed_len..].to_vec(),
+        };
+
+        header.kdf.check()?;
+        Ok(header)
+    }
+
+    fn derive_key(&self, passphrase: &[u8]) -> Result<[u8; 32], Error> {
+        let params = Params::new(self.kdf.m_cost, self.kdf.t_cost, self.kdf.p_cost, Some(32))
+            .map_err(|e| Error::EncryptionError(e.to_string()))?;
+
+        let mut key = [0u8; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase, &self.salt, &mut key)
+            .map_err(|e| Error::EncryptionError(e.to_string()))?;
+
+        Ok(key)
+    }
+}
+
+impl EncryptKV {
+    /// Opens the encryption layer, writing a new header with a fresh salt
+    /// when the store has none. A store holding unencrypted records is
+    /// refused.
+    pub fn new(kv: Arc<Box<dyn KeyValue>>, passphrase: &[u8]) -> Result<Self, Error> {
+        EncryptKV::new_with(kv, passphrase, KdfParams::default())
+    }
+
+    /// Like `new`, a new header records `kdf` as its cost.
+    pub fn new_with(kv: Arc<Box<dyn KeyValue>>, passphrase: &[u8], kdf: KdfParams) -> Result<Self, Error> {
+        match kv.get(0, HEADER_KEY)? {
+            Some(buf) => EncryptKV::open(kv, &Header::from_buf(&buf)?, passphrase),
+            None => EncryptKV::create(kv, passphrase, kdf),
+        }
+    }
+
+    pub fn new_box(kv: Arc<Box<dyn KeyValue>>, passphrase: &[u8]) -> Result<Box<dyn KeyValue>, Error> {
+        Ok(Box::new(EncryptKV::new(kv, passphrase)?))
+    }
+
+    /// True when the store has an encryption header, it must then only be
+    /// opened through `EncryptKV`.
+    pub fn is_encrypted(kv: &dyn KeyValue) -> Result<bool, Error> {
+        Ok(kv.get(0, HEADER_KEY)?.is_some())
+    }
+
+    fn open(kv: Arc<Box<dyn KeyValue>>, header: &Header, passphrase: &[u8]) -> Result<Self, Error> {
+        let key = header.derive_key(passphrase)?;
+        let ekv = EncryptKV {
+            kv,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        };
+
+        match ekv.decrypt(0, HEADER_KEY, &header.check) {
+            Ok(ref plain) if plain == CHECK_PLAINTEXT => Ok(ekv),
+            _ => Err(Error::EncryptionError("wrong passphrase".to_owned())),
+        }
+    }
+
+    fn create(kv: Arc<Box<dyn KeyValue>>, passphrase: &[u8], kdf: KdfParams) -> Result<Self, Error> {
+        debug!("encrypt creating header");
+
+        if !kv.scan(0, &Scan::all().limit(1))?.is_empty() {
+            return Err(Error::EncryptionError("store has unencrypted data".to_owned()));
+        }
+
+        // Refused here rather than by every later open
+        kdf.check()?;
+
+        let mut header = Header {
+            kdf,
+            salt: random_bytes(SALT_LEN)?,
+            check: Vec::new(),
+        };
+
+        let key = header.derive_key(passphrase)?;
+        let ekv = EncryptKV {
+            kv,
+            cipher: XChaCha20Poly1305::new(Key::from_slice(&key)),
+        };
+
+        header.check = ekv.encrypt(0, HEADER_KEY, CHECK_PLAINTEXT)?;
+        ekv.kv.put(0, HEADER_KEY, &header.to_vec())?;
+        ekv.kv.sync()?;
+
+        Ok(ekv)
+    }
+
+    fn encrypt(&self, ver: u64, key: &[u8], val: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = random_bytes(NONCE_LEN)?;
+        let aad = associated_data(ver, key);
+
+        let ct = self.cipher.encrypt(XNonce::from_slice(&nonce), Payload { msg: val, aad: &aad })
+            .map_err(|e| Error::EncryptionError(e.to_string()))?;
+
+        let mut buf = nonce;
+        buf.extend_from_slice(&ct);
+        Ok(buf)
+    }
+
+    fn decrypt(&self, ver: u64, key: &[u8], buf: &[u8]) -> Result<Vec<u8>, Error> {
+        if buf.len() < NONCE_LEN {
+            return Err(Error::EncryptionError(format!("value too short v={}", ver)));
+        }
+
+        let (nonce, ct) = buf.split_at(NONCE_LEN);
+        let aad = associated_data(ver, key);
+
+        self.cipher.decrypt(XNonce::from_slice(nonce), Payload { msg: ct, aad: &aad })
+            .map_err(|_| Error::EncryptionError(format!("authentication failed v={}", ver)))
+    }
+}
+
+impl KeyValue for EncryptKV {
+    fn put(&self, ver: u64, key: &[u8], val: &[u8]) -> Result<(), Error> {
+        debug!("encrypt put {} {} {}", ver, key.len(), val.len());
+
+        let buf = self.encrypt(ver, key, val)?;
+        self.kv.put(ver, key, &buf)
+    }
+
+    fn get(&self, ver: u64, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        debug!("encrypt get {} {}", ver, key.len());
+
+        match self.kv.get(ver, key)? {
+            None => Ok(None),
+            Some(buf) => Ok(Some(self.decrypt(ver, key, &buf)?)),
+        }
+    }
+
+    fn delete(&self, ver: u64, key: &[u8]) -> Result<(), Error> {
+        self.kv.delete(ver, key)
+    }
+
+    fn scan(&self, ver: u64, scan: &Scan) -> Result<ScanItems, Error> {
+        let mut items = self.kv.scan(ver, scan)?;
+
+        if ver == 0 {
+            items.retain(|(k, _)| k != HEADER_KEY);
+        }
+
+        for item in items.iter_mut() {
+            item.1 = self.decrypt(ver, &item.0, &item.1)?;
+        }
+
+        Ok(items)
+    }
+
+    fn write(&self, mut batch: WriteBatch) -> Result<(), Error> {
+        for op in batch.ops.iter_mut() {
+            match op {
+                BatchOp::Put(ver, key, val) => *val = self.encrypt(*ver, key, val)?,
+                BatchOp::Expect(ver, key, Some(val)) => {
+                    *val = expect_stored(&**self.kv, *ver, key, val, |buf| self.decrypt(*ver, key, &buf))?;
+                },
+                _ => {},
+            }
+        }
+
+        self.kv.write(batch)
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        self.kv.sync()
+    }
+
+    fn create_version(&self, ver: u64) -> Result<(), Error> {
+        self.kv.create_version(ver)
+    }
+}
+
+fn associated_data(ver: u64, key: &[u8]) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(8 + key.len());
+    aad.extend_from_slice(&ver.to_be_bytes());
+    aad.extend_from_slice(key);
+    aad
+}
+
+fn random_bytes(len: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0u8; len];
+    getrandom::getrandom(&mut buf).map_err(|e| Error::EncryptionError(e.to_string()))?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryKV;
+
+    /// For the tests not about the cost itself
+    const CHEAP: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn inner() -> Arc<Box<dyn KeyValue>> {
+        Arc::new(MemoryKV::new_box())
+    }
+
+    fn encrypted(kv: &Arc<Box<dyn KeyValue>>, passphrase: &[u8]) -> Result<EncryptKV, Error> {
+        EncryptKV::new_with(kv.clone(), passphrase, CHEAP)
+    }
+
+    #[test]
+    fn round_trip() {
+        let kv = inner();
+        let ekv = encrypted(&kv, b"secret").unwrap();
+
+        ekv.put(1, b"a", b"one").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(1, b"b", b"two");
+        ekv.write(batch).unwrap();
+
+        assert_eq!(ekv.get(1, b"a").unwrap(), Some(b"one".to_vec()));
+        assert_ne!(kv.get(1, b"a").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(ekv.scan(1, &Scan::all()).unwrap(), vec![
+            (b"a".to_vec(), b"one".to_vec()),
+            (b"b".to_vec(), b"two".to_vec()),
+        ]);
+
+        // Reopening reads the header written on create
+        let ekv = encrypted(&kv, b"secret").unwrap();
+        assert_eq!(ekv.get(1, b"b").unwrap(), Some(b"two".to_vec()));
+        assert!(ekv.scan(0, &Scan::all()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let kv = inner();
+        encrypted(&kv, b"secret").unwrap();
+
+        assert!(encrypted(&kv, b"guess").is_err());
+    }
+
+    #[test]
+    fn value_bound_to_key_and_version() {
+        let kv = inner();
+        let ekv = encrypted(&kv, b"secret").unwrap();
+        ekv.put(1, b"a", b"one").unwrap();
+
+        let buf = kv.get(1, b"a").unwrap().unwrap();
+        kv.put(1, b"b", &buf).unwrap();
+        kv.put(2, b"a", &buf).unwrap();
+
+        assert!(ekv.get(1, b"b").is_err());
+        assert!(ekv.get(2, b"a").is_err());
+        assert_eq!(ekv.get(1, b"a").unwrap(), Some(b"one".to_vec()));
+    }
+
+    #[test]
+    fn refuses_unencrypted_store() {
+        let kv = inner();
+        kv.put(0, b"init", b"1").unwrap();
+
+        assert!(encrypted(&kv, b"secret").is_err());
+        assert!(!EncryptKV::is_encrypted(&**kv).unwrap());
+    }
+
+    #[test]
+    fn header_cost_bounded() {
+        let header = Header {
+            kdf: KdfParams { m_cost: u32::MAX, ..KdfParams::default() },
+            salt: vec![0; SALT_LEN],
+            check: vec![0; 16],
+        };
+
+        assert!(Header::from_buf(&header.to_vec()).is_err());
+        assert!(EncryptKV::new_with(inner(), b"secret", header.kdf).is_err());
+    }
+
+    #[test]
+    fn default_cost() {
+        let kv = inner();
+        let ekv = EncryptKV::new(kv.clone(), b"secret").unwrap();
+        ekv.put(1, b"a", b"one").unwrap();
+
+        // Opened with the cost from the header, whatever is passed
+        let header = Header::from_buf(&kv.get(0, HEADER_KEY).unwrap().unwrap()).unwrap();
+        assert_eq!(header.kdf, KdfParams::default());
+        let ekv = encrypted(&kv, b"secret").unwrap();
+        assert_eq!(ekv.get(1, b"a").unwrap(), Some(b"one".to_vec()));
+    }
+}
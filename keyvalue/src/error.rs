@@ -7,6 +7,15 @@ pub enum Error {
     #[error("KeyValue Impl error")]
     ImplError(String),
 
+    #[error("Encryption error {0}")]
+    EncryptionError(String),
+
+    #[error("Corrupted value v={ver} key={key:?}")]
+    Corruption { ver: u64, key: Vec<u8> },
+
+    #[error("Value changed v={ver} key={key:?}")]
+    Conflict { ver: u64, key: Vec<u8> },
+
     #[error("Unknown KeyValue error")]
     Unknown,
 }
\ No newline at end of file
@@ -2,20 +2,38 @@ mod error;
 pub mod sqlite;
 pub mod rocksdb;
 pub mod compress;
+pub mod memory;
+pub mod encrypt;
+pub mod checksum;
+pub mod cache;
+pub mod scan;
+pub mod batch;
 
 pub use error::Error;
+pub use scan::Scan;
+pub use batch::{WriteBatch, BatchOp};
+
+/// Key/value pairs as returned by `KeyValue::scan`
+pub type ScanItems = Vec<(Vec<u8>, Vec<u8>)>;
 
 
 pub trait KeyValue {
-    fn put(&self, ver: u16, key: &[u8], val: &[u8]) -> Result<(), Error>;
-    fn get(&self, ver: u16, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
-    fn delete(&self, ver: u16, key: &[u8]) -> Result<(), Error>;
+    fn put(&self, ver: u64, key: &[u8], val: &[u8]) -> Result<(), Error>;
+    fn get(&self, ver: u64, key: &[u8]) -> Result<Option<Vec<u8>>, Error>;
+    fn delete(&self, ver: u64, key: &[u8]) -> Result<(), Error>;
+
+    /// Key/value pairs of version `ver` within the scan range, in key order
+    /// (descending when reversed).
+    fn scan(&self, ver: u64, scan: &Scan) -> Result<ScanItems, Error>;
+
+    /// Applies every operation of the batch or none of them.
+    fn write(&self, batch: WriteBatch) -> Result<(), Error>;
 
-    fn put_str(&self, ver: u16, key: &str, val: &[u8]) -> Result<(), Error> {
+    fn put_str(&self, ver: u64, key: &str, val: &[u8]) -> Result<(), Error> {
         self.put(ver, key.as_bytes(), val)
     }
 
-    fn get_str(&self, ver: u16, key: &str) -> Result<Option<Vec<u8>>, Error> {
+    fn get_str(&self, ver: u64, key: &str) -> Result<Option<Vec<u8>>, Error> {
         self.get(ver, key.as_bytes())
     }
 
@@ -23,12 +41,12 @@ pub trait KeyValue {
         Ok(())
     }
 
-    fn create_version(&self, _ver: u16) -> Result<(), Error> {
+    fn create_version(&self, _ver: u64) -> Result<(), Error> {
         Ok(())
     }
 }
 
-pub fn make_key(ver: u16, key: &[u8]) -> Vec<u8> {
+pub fn make_key(ver: u64, key: &[u8]) -> Vec<u8> {
     let mut buf: Vec<u8> = Vec::new();
 
     buf.extend_from_slice(&ver.to_be_bytes()[..]);
@@ -0,0 +1,312 @@
+use std::sync::Arc;
+use std::ops::Bound;
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{PathBuf, Path};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use parking_lot::RwLock;
+use log::debug;
+
+use crate::{Error, KeyValue, Scan, ScanItems, WriteBatch, BatchOp};
+use crate::batch::check_expected;
+
+/// Snapshot files start with this magic, followed by the version list and
+/// the entries, all lengths and versions big endian.
+const SNAPSHOT_MAGIC: &[u8] = b"TAPMEMKV1";
+
+#[derive(Default)]
+struct MemoryInner {
+    data: BTreeMap<(u64, Vec<u8>), Vec<u8>>,
+    versions: BTreeSet<u64>,
+}
+
+/// Ordered in-memory store. Optionally backed by a snapshot file which is
+/// loaded on open and rewritten on every `sync`.
+#[derive(Clone, Default)]
+pub struct MemoryKV {
+    path: Option<PathBuf>,
+    inner: Arc<RwLock<MemoryInner>>,
+}
+
+impl MemoryKV {
+    pub fn new() -> Self {
+        MemoryKV::default()
+    }
+
+    pub fn new_box() -> Box<dyn KeyValue> {
+        Box::new(MemoryKV::new())
+    }
+
+    pub fn with_file(path: &Path) -> Result<Self, Error> {
+        let mut kv = if path.exists() {
+            MemoryKV::load_from_file(path)?
+        }else{
+            MemoryKV::new()
+        };
+
+        kv.path = Some(path.to_owned());
+        Ok(kv)
+    }
+
+    pub fn with_file_box(path: &Path) -> Result<Box<dyn KeyValue>, Error> {
+        Ok(Box::new(MemoryKV::with_file(path)?))
+    }
+
+    pub fn load_from_file(path: &Path) -> Result<Self, Error> {
+        debug!("memory kv load {:?}", path);
+        let f = File::open(path)?;
+        let mut r = SnapshotReader {
+            remaining: f.metadata()?.len(),
+            r: BufReader::new(f),
+        };
+
+        let mut magic = [0u8; 9];
+        r.read_exact(&mut magic)?;
+        if magic[..] != SNAPSHOT_MAGIC[..] {
+            return Err(Error::ImplError(format!("{:?} is not a memory kv snapshot", path)));
+        }
+
+        let mut inner = MemoryInner::default();
+
+        for _ in 0..r.read_u64()? {
+            inner.versions.insert(r.read_u64()?);
+        }
+
+        for _ in 0..r.read_u64()? {
+            let ver = r.read_u64()?;
+            let key = r.read_buf()?;
+            let val = r.read_buf()?;
+            inner.data.insert((ver, key), val);
+        }
+
+        Ok(MemoryKV {
+            path: None,
+            inner: Arc::new(RwLock::new(inner)),
+        })
+    }
+
+    /// Writes the whole store to `path`, replacing it only once the
+    /// snapshot is complete.
+    pub fn snapshot_to_file(&self, path: &Path) -> Result<(), Error> {
+        debug!("memory kv snapshot {:?}", path);
+        // Suffixed rather than a new extension, so snapshots sharing a stem
+        // do not share a temporary file
+        let mut tmp_name = path.file_name()
+            .ok_or_else(|| Error::ImplError(format!("{:?} is not a file path", path)))?
+            .to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+
+        {
+            let inner = self.inner.read();
+            let mut w = BufWriter::new(File::create(&tmp_path)?);
+
+            w.write_all(SNAPSHOT_MAGIC)?;
+
+            w.write_all(&(inner.versions.len() as u64).to_be_bytes())?;
+            for ver in inner.versions.iter() {
+                w.write_all(&ver.to_be_bytes())?;
+            }
+
+            w.write_all(&(inner.data.len() as u64).to_be_bytes())?;
+            for ((ver, key), val) in inner.data.iter() {
+                w.write_all(&ver.to_be_bytes())?;
+                write_buf(&mut w, key)?;
+                write_buf(&mut w, val)?;
+            }
+
+            w.flush()?;
+            w.get_ref().sync_all()?;
+        }
+
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn versions(&self) -> Vec<u64> {
+        let inner = self.inner.read();
+        inner.versions.iter().cloned().collect()
+    }
+}
+
+impl KeyValue for MemoryKV {
+    fn put(&self, ver: u64, key: &[u8], val: &[u8]) -> Result<(), Error> {
+        let mut inner = self.inner.write();
+        inner.versions.insert(ver);
+        inner.data.insert((ver, key.to_owned()), val.to_owned());
+        Ok(())
+    }
+
+    fn get(&self, ver: u64, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let inner = self.inner.read();
+        Ok(inner.data.get(&(ver, key.to_owned())).cloned())
+    }
+
+    fn delete(&self, ver: u64, key: &[u8]) -> Result<(), Error> {
+        let mut inner = self.inner.write();
+        inner.data.remove(&(ver, key.to_owned()));
+        Ok(())
+    }
+
+    fn scan(&self, ver: u64, scan: &Scan) -> Result<ScanItems, Error> {
+        let start = Bound::Included((ver, scan.start.clone()));
+        let end = match &scan.end {
+            Some(end) => Bound::Excluded((ver, end.clone())),
+            None => match ver.checked_add(1) {
+                Some(next) => Bound::Excluded((next, Vec::new())),
+                None => Bound::Unbounded,
+            },
+        };
+
+        let inner = self.inner.read();
+        let range = inner.data.range((start, end));
+        let limit = scan.limit.unwrap_or(usize::MAX);
+
+        let items = if scan.reverse {
+            range.rev().take(limit).map(|((_, k), v)| (k.clone(), v.clone())).collect()
+        }else{
+            range.take(limit).map(|((_, k), v)| (k.clone(), v.clone())).collect()
+        };
+
+        Ok(items)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), Error> {
+        let mut inner = self.inner.write();
+        check_expected(&batch, |ver, key| Ok(inner.data.get(&(ver, key.to_owned())).cloned()))?;
+
+        for op in batch.ops.into_iter() {
+            match op {
+                BatchOp::Put(ver, key, val) => {
+                    inner.versions.insert(ver);
+                    inner.data.insert((ver, key), val);
+                },
+                BatchOp::Delete(ver, key) => {
+                    inner.data.remove(&(ver, key));
+                },
+                BatchOp::Expect(..) => {},
+            }
+        }
+
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<(), Error> {
+        match &self.path {
+            Some(path) => self.snapshot_to_file(path),
+            None => Ok(()),
+        }
+    }
+
+    fn create_version(&self, ver: u64) -> Result<(), Error> {
+        let mut inner = self.inner.write();
+        inner.versions.insert(ver);
+        Ok(())
+    }
+}
+
+/// Snapshot file reader tracking how much of the file is left, lengths
+/// read from the file are checked against it before allocating.
+struct SnapshotReader {
+    r: BufReader<File>,
+    remaining: u64,
+}
+
+impl SnapshotReader {
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        if buf.len() as u64 > self.remaining {
+            return Err(Error::ImplError("truncated memory kv snapshot".to_owned()));
+        }
+
+        self.r.read_exact(buf)?;
+        self.remaining -= buf.len() as u64;
+        Ok(())
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Error> {
+        let mut buf = [0u8; 8];
+        self.read_exact(&mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    fn read_buf(&mut self) -> Result<Vec<u8>, Error> {
+        let mut len = [0u8; 4];
+        self.read_exact(&mut len)?;
+
+        let len = u32::from_be_bytes(len) as u64;
+        if len > self.remaining {
+            return Err(Error::ImplError("truncated memory kv snapshot".to_owned()));
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        self.read_exact(&mut buf)?;
+        Ok(buf)
+    }
+}
+
+fn write_buf(w: &mut impl Write, buf: &[u8]) -> Result<(), Error> {
+    w.write_all(&(buf.len() as u32).to_be_bytes())?;
+    w.write_all(buf)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("memkv-{}-{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let path = snapshot_path("round-trip");
+        let kv = MemoryKV::with_file(&path).unwrap();
+        kv.put(1, b"a", b"one").unwrap();
+        kv.create_version(2).unwrap();
+        kv.sync().unwrap();
+
+        let kv = MemoryKV::load_from_file(&path).unwrap();
+        assert_eq!(kv.get(1, b"a").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(kv.versions(), vec![1, 2]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn length_past_end_of_file() {
+        let path = snapshot_path("bad-length");
+        let mut buf = SNAPSHOT_MAGIC.to_vec();
+        buf.extend_from_slice(&0u64.to_be_bytes());
+        buf.extend_from_slice(&1u64.to_be_bytes());
+        buf.extend_from_slice(&1u64.to_be_bytes());
+        buf.extend_from_slice(&u32::MAX.to_be_bytes());
+        fs::write(&path, &buf).unwrap();
+
+        assert!(MemoryKV::load_from_file(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn snapshots_sharing_a_stem() {
+        let snap = snapshot_path("stem.snap");
+        let db = snapshot_path("stem.db");
+        let other = snapshot_path("stem.tmp");
+        fs::write(&other, b"other").unwrap();
+
+        for (path, val) in [(&snap, &b"snap"[..]), (&db, &b"db"[..])].iter() {
+            let kv = MemoryKV::with_file(path).unwrap();
+            kv.put(1, b"a", val).unwrap();
+            kv.sync().unwrap();
+        }
+
+        assert_eq!(MemoryKV::load_from_file(&snap).unwrap().get(1, b"a").unwrap(), Some(b"snap".to_vec()));
+        assert_eq!(fs::read(&other).unwrap(), b"other".to_vec());
+
+        for path in [snap, db, other].iter() {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
@@ -1,12 +1,25 @@
 
 use std::sync::Arc;
 use std::path::Path;
-use rocksdb::DB;
+use rocksdb::{DB, Direction, IteratorMode, WriteBatch as DBWriteBatch};
+use parking_lot::Mutex;
+use log::debug;
 
-use crate::{KeyValue, make_key, Error};
+use crate::{KeyValue, make_key, Error, Scan, ScanItems, WriteBatch, BatchOp};
+use crate::batch::check_expected;
+
+/// Raw key holding the on-disk key layout. It cannot collide with keys built
+/// by `make_key`, those always have ':' after the 8 byte version.
+const LAYOUT_KEY: &[u8] = b"layout";
+
+/// Keys prefixed with a 64 bit version. Databases without a layout record
+/// were written with a 16 bit version prefix.
+const LAYOUT_VER64: u8 = 2;
 
 pub struct RocksDB {
     db: Arc<DB>,
+    /// Held by batches with expectations from checking to writing
+    write_lock: Mutex<()>,
 }
 
 impl RocksDB {
@@ -14,9 +27,13 @@ impl RocksDB {
         let db = DB::open_default(dirpath)
             .map_err(|e| Error::ImplError(e.to_string()))?;
 
-        Ok(RocksDB{
+        let rdb = RocksDB{
             db: Arc::new(db),
-        })
+            write_lock: Mutex::new(()),
+        };
+
+        rdb.upgrade()?;
+        Ok(rdb)
     }
 
     pub fn new_box(dirpath: &Path) -> Result<Box<dyn KeyValue>, Error> {
@@ -24,10 +41,40 @@ impl RocksDB {
 
         Ok(db)
     }
+
+    /// Rewrites keys of a database created with 16 bit versions to the
+    /// 64 bit layout, in place and in a single atomic write.
+    fn upgrade(&self) -> Result<(), Error> {
+        match self.db.get(LAYOUT_KEY).map_err(|e| Error::ImplError(e.to_string()))? {
+            Some(buf) if buf[..] == [LAYOUT_VER64] => return Ok(()),
+            Some(buf) => return Err(Error::ImplError(format!("unknown key layout {:?}", buf))),
+            None => {},
+        }
+
+        let mut batch = DBWriteBatch::default();
+        let mut count = 0;
+
+        for (k, v) in self.db.iterator(IteratorMode::Start) {
+            if k.len() < 3 || k[2] != 58 {
+                continue;
+            }
+
+            let ver = u16::from_be_bytes([k[0], k[1]]) as u64;
+            batch.put(make_key(ver, &k[3..]), v);
+            batch.delete(k);
+            count += 1;
+        }
+
+        debug!("upgrading {} keys to 64 bit versions", count);
+        batch.put(LAYOUT_KEY, [LAYOUT_VER64]);
+        self.db.write(batch).map_err(|e| Error::ImplError(e.to_string()))?;
+
+        Ok(())
+    }
 }
 
 impl KeyValue for RocksDB {
-    fn put(&self, ver: u16, key: &[u8], val: &[u8]) -> Result<(), Error> {
+    fn put(&self, ver: u64, key: &[u8], val: &[u8]) -> Result<(), Error> {
         let real_key = make_key(ver, key);
 
         self.db.put(&real_key, &val)
@@ -36,7 +83,7 @@ impl KeyValue for RocksDB {
         Ok(())
     }
 
-    fn get(&self, ver: u16, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    fn get(&self, ver: u64, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
         let real_key = make_key(ver, key);
 
         let buf = self.db.get(&real_key).map_err(|e| Error::ImplError(e.to_string()))?;
@@ -44,9 +91,108 @@ impl KeyValue for RocksDB {
         Ok(buf)
     }
 
-    fn delete(&self, ver: u16, key: &[u8]) -> Result<(), Error> {
+    fn delete(&self, ver: u64, key: &[u8]) -> Result<(), Error> {
         let real_key = make_key(ver, key);
         self.db.delete(&real_key).map_err(|e| Error::ImplError(e.to_string()))?;
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn write(&self, batch: WriteBatch) -> Result<(), Error> {
+        let _lock = self.write_lock.lock();
+        check_expected(&batch, |ver, key| self.get(ver, key))?;
+
+        let mut db_batch = DBWriteBatch::default();
+
+        for op in batch.ops.iter() {
+            match op {
+                BatchOp::Put(ver, key, val) => db_batch.put(make_key(*ver, key), val),
+                BatchOp::Delete(ver, key) => db_batch.delete(make_key(*ver, key)),
+                BatchOp::Expect(..) => {},
+            }
+        }
+
+        self.db.write(db_batch).map_err(|e| Error::ImplError(e.to_string()))?;
+        Ok(())
+    }
+
+    fn scan(&self, ver: u64, scan: &Scan) -> Result<ScanItems, Error> {
+        let ver_prefix = make_key(ver, &[]);
+        let start = make_key(ver, &scan.start);
+
+        // Upper bound of the range as a raw key, None past the last version
+        let end = match &scan.end {
+            Some(end) => Some(make_key(ver, end)),
+            None => ver.checked_add(1).map(|next| make_key(next, &[])),
+        };
+
+        let mode = if scan.reverse {
+            match &end {
+                Some(end) => IteratorMode::From(end, Direction::Reverse),
+                None => IteratorMode::End,
+            }
+        } else {
+            IteratorMode::From(&start, Direction::Forward)
+        };
+
+        let mut items = Vec::new();
+
+        for (k, v) in self.db.iterator(mode) {
+            if let Some(end) = &end {
+                // reverse seek lands on the end key itself when present
+                if scan.reverse && k[..] >= end[..] {
+                    continue;
+                }
+            }
+
+            if !k.starts_with(&ver_prefix) || !scan.contains(&k[ver_prefix.len()..]) {
+                break;
+            }
+
+            items.push((k[ver_prefix.len()..].to_vec(), v.to_vec()));
+            if scan.is_full(items.len()) {
+                break;
+            }
+        }
+
+        Ok(items)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn old_key(ver: u16, key: &[u8]) -> Vec<u8> {
+        let mut buf = ver.to_be_bytes().to_vec();
+        buf.push(58);
+        buf.extend_from_slice(key);
+        buf
+    }
+
+    #[test]
+    fn upgrade_u16_keys() {
+        let path = std::env::temp_dir().join(format!("rocksdb-{}-u16", std::process::id()));
+        {
+            let db = DB::open_default(&path).unwrap();
+            db.put(old_key(0, b"commits"), b"state").unwrap();
+            db.put(old_key(u16::MAX, b"a"), b"one").unwrap();
+        }
+
+        let kv = RocksDB::new(&path).unwrap();
+        assert_eq!(kv.get(0, b"commits").unwrap(), Some(b"state".to_vec()));
+        assert_eq!(kv.get(u16::MAX as u64, b"a").unwrap(), Some(b"one".to_vec()));
+
+        // Its third byte is ':' like a 16 bit key, a second upgrade would move it
+        let ver = 58 << 40;
+        kv.put(ver, b"b", b"two").unwrap();
+        drop(kv);
+
+        let kv = RocksDB::new(&path).unwrap();
+        assert_eq!(kv.get(ver, b"b").unwrap(), Some(b"two".to_vec()));
+        assert_eq!(kv.get(u16::MAX as u64, b"a").unwrap(), Some(b"one".to_vec()));
+        assert_eq!(kv.db.iterator(IteratorMode::Start).count(), 4);
+
+        drop(kv);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+
+/// Key range read by `KeyValue::scan` within a single version.
+///
+/// `start` is inclusive and `end` exclusive, `None` leaves the range open
+/// ended. Results are collected, use `limit` to page through large ranges.
+#[derive(Debug, Clone, Default)]
+pub struct Scan {
+    pub start: Vec<u8>,
+    pub end: Option<Vec<u8>>,
+    pub reverse: bool,
+    pub limit: Option<usize>,
+}
+
+impl Scan {
+    pub fn all() -> Self {
+        Scan::default()
+    }
+
+    pub fn prefix(prefix: &[u8]) -> Self {
+        Scan {
+            start: prefix.to_owned(),
+            end: prefix_end(prefix),
+            ..Scan::default()
+        }
+    }
+
+    pub fn range(start: &[u8], end: &[u8]) -> Self {
+        Scan {
+            start: start.to_owned(),
+            end: Some(end.to_owned()),
+            ..Scan::default()
+        }
+    }
+
+    pub fn reverse(mut self) -> Self {
+        self.reverse = true;
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        if key < &self.start[..] {
+            return false;
+        }
+
+        match &self.end {
+            Some(end) => key < &end[..],
+            None => true,
+        }
+    }
+
+    pub fn is_full(&self, count: usize) -> bool {
+        match self.limit {
+            Some(limit) => count >= limit,
+            None => false,
+        }
+    }
+}
+
+/// Smallest key greater than every key starting with `prefix`, `None` when
+/// there is none (empty or all 0xff prefix).
+pub fn prefix_end(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut end = prefix.to_owned();
+
+    while let Some(b) = end.pop() {
+        if b < 0xff {
+            end.push(b + 1);
+            return Some(end);
+        }
+    }
+
+    None
+}
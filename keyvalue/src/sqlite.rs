@@ -1,10 +1,19 @@
 use std::sync::Arc;
 use std::path::{PathBuf, Path};
 
-use rusqlite::{params, Connection, NO_PARAMS};
+use rusqlite::{params, Connection, ErrorCode, NO_PARAMS};
 use parking_lot::Mutex;
 
-use crate::{Error, KeyValue};
+use crate::{Error, KeyValue, Scan, ScanItems, WriteBatch, BatchOp};
+use crate::batch::check_expected;
+
+/// SQLite integers are signed 64 bit, versions are stored as such.
+fn sql_ver(ver: u64) -> Result<i64, Error> {
+    if ver > i64::MAX as u64 {
+        return Err(Error::ImplError(format!("version {} out of range for sqlite", ver)));
+    }
+    Ok(ver as i64)
+}
 
 #[derive(Clone)]
 pub struct SqliteDB {
@@ -39,6 +48,11 @@ impl SqliteDB {
             primary key(ver, key)
         )", NO_PARAMS).map_err(|e| Error::ImplError(e.to_string()))?;
 
+        // Readers keep a transaction open until sync, in WAL mode that does
+        // not keep other processes from committing
+        db.query_row("pragma journal_mode=wal", NO_PARAMS, |row| row.get::<_, String>(0))
+            .map_err(|e| Error::ImplError(e.to_string()))?;
+
         self.begin_tx(&db)?;
 
         Ok(())
@@ -53,37 +67,29 @@ impl SqliteDB {
         db.execute("end transaction", NO_PARAMS).map_err(|e| Error::ImplError(e.to_string()))?;
         Ok(())
     }
-}
-
-
-impl KeyValue for SqliteDB {
-    fn put(&self, ver: u16, key: &[u8], value: &[u8]) -> Result<(), Error> {
 
+    fn put_with(&self, db: &Connection, ver: u64, key: &[u8], value: &[u8]) -> Result<(), Error> {
         let sql = "insert or replace into data values(?,?,?)";
-        let db = self.db.lock();
         let mut stmt = db.prepare_cached(sql).map_err(|e| Error::ImplError(e.to_string()))?;
 
-        stmt.execute(params![ver, key, value]).map_err(|e| Error::ImplError(e.to_string()))?;
+        stmt.execute(params![sql_ver(ver)?, key, value]).map_err(|e| Error::ImplError(e.to_string()))?;
 
         Ok(())
     }
 
-    fn delete(&self, ver: u16, key: &[u8]) -> Result<(), Error> {
+    fn delete_with(&self, db: &Connection, ver: u64, key: &[u8]) -> Result<(), Error> {
         let sql = "delete from data where ver=? and key=?";
-        let db = self.db.lock();
         let mut stmt = db.prepare_cached(sql).map_err(|e| Error::ImplError(e.to_string()))?;
-        stmt.execute(params![ver, key]).map_err(|e| Error::ImplError(e.to_string()))?;
+        stmt.execute(params![sql_ver(ver)?, key]).map_err(|e| Error::ImplError(e.to_string()))?;
 
         Ok(())
     }
 
-    fn get(&self, ver: u16, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    fn get_with(&self, db: &Connection, ver: u64, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
         let sql = "select ver, key, value from data where ver=? and key=?";
-        let db = self.db.lock();
-
         let mut stmt = db.prepare_cached(sql).map_err(|e| Error::ImplError(e.to_string()))?;
 
-        let mut rows = stmt.query(params![ver, key]).map_err(|e| Error::ImplError(e.to_string()))?;
+        let mut rows = stmt.query(params![sql_ver(ver)?, key]).map_err(|e| Error::ImplError(e.to_string()))?;
         match rows.next().map_err(|e| Error::ImplError(e.to_string()))? {
             None => {
                 Ok(None)
@@ -95,10 +101,199 @@ impl KeyValue for SqliteDB {
         }
     }
 
+    /// Applies a batch with expectations in a transaction of its own, so
+    /// they are checked against what other connections committed and not
+    /// against the snapshot of the open transaction. Commits what was
+    /// written before it.
+    fn write_checked(&self, db: &Connection, batch: &WriteBatch) -> Result<(), Error> {
+        self.end_tx(db)?;
+
+        // Another connection writing for longer than the busy timeout
+        // counts as having changed the expected records
+        match db.execute("begin immediate", NO_PARAMS) {
+            Ok(_) => {},
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::DatabaseBusy => {
+                self.begin_tx(db)?;
+                return Err(busy_conflict(batch));
+            },
+            Err(e) => return Err(Error::ImplError(e.to_string())),
+        }
+
+        let res = match self.apply(db, batch) {
+            Ok(()) => self.end_tx(db),
+            Err(e) => {
+                db.execute("rollback", NO_PARAMS).map_err(|e| Error::ImplError(e.to_string()))?;
+                Err(e)
+            }
+        };
+
+        self.begin_tx(db)?;
+        res
+    }
+
+    fn apply(&self, db: &Connection, batch: &WriteBatch) -> Result<(), Error> {
+        check_expected(batch, |ver, key| self.get_with(db, ver, key))?;
+
+        for op in batch.ops.iter() {
+            match op {
+                BatchOp::Put(ver, key, val) => self.put_with(db, *ver, key, val)?,
+                BatchOp::Delete(ver, key) => self.delete_with(db, *ver, key)?,
+                BatchOp::Expect(..) => {},
+            }
+        }
+
+        Ok(())
+    }
+}
+
+
+impl KeyValue for SqliteDB {
+    fn put(&self, ver: u64, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        let db = self.db.lock();
+        self.put_with(&db, ver, key, value)
+    }
+
+    fn delete(&self, ver: u64, key: &[u8]) -> Result<(), Error> {
+        let db = self.db.lock();
+        self.delete_with(&db, ver, key)
+    }
+
+    fn write(&self, batch: WriteBatch) -> Result<(), Error> {
+        let db = self.db.lock();
+
+        if batch.ops.iter().any(|op| matches!(op, BatchOp::Expect(..))) {
+            return self.write_checked(&db, &batch);
+        }
+
+        // Nested in the open transaction, so a failed batch leaves no trace
+        db.execute("savepoint batch", NO_PARAMS).map_err(|e| Error::ImplError(e.to_string()))?;
+
+        match self.apply(&db, &batch) {
+            Ok(()) => {
+                db.execute("release batch", NO_PARAMS).map_err(|e| Error::ImplError(e.to_string()))?;
+                Ok(())
+            },
+            Err(e) => {
+                db.execute("rollback to batch", NO_PARAMS).map_err(|e| Error::ImplError(e.to_string()))?;
+                db.execute("release batch", NO_PARAMS).map_err(|e| Error::ImplError(e.to_string()))?;
+                Err(e)
+            }
+        }
+    }
+
+    fn get(&self, ver: u64, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let db = self.db.lock();
+        self.get_with(&db, ver, key)
+    }
+
+    fn scan(&self, ver: u64, scan: &Scan) -> Result<ScanItems, Error> {
+        let end_cond = if scan.end.is_some() { "and key<?3" } else { "" };
+        let order = if scan.reverse { "desc" } else { "asc" };
+        let sql = format!("select key, value from data where ver=?1 and key>=?2 {} order by key {} limit ?4",
+            end_cond, order);
+
+        let limit = scan.limit.map(|l| l as i64).unwrap_or(-1);
+        let end = scan.end.clone().unwrap_or_default();
+
+        let db = self.db.lock();
+        let mut stmt = db.prepare_cached(&sql).map_err(|e| Error::ImplError(e.to_string()))?;
+
+        let mut rows = stmt.query(params![sql_ver(ver)?, scan.start, end, limit])
+            .map_err(|e| Error::ImplError(e.to_string()))?;
+
+        let mut items = Vec::new();
+        while let Some(row) = rows.next().map_err(|e| Error::ImplError(e.to_string()))? {
+            let key: Vec<u8> = row.get(0).map_err(|e| Error::ImplError(e.to_string()))?;
+            let val: Vec<u8> = row.get(1).map_err(|e| Error::ImplError(e.to_string()))?;
+            items.push((key, val));
+        }
+
+        Ok(items)
+    }
+
     fn sync(&self) -> Result<(), Error> {
         let db = self.db.lock();
         self.end_tx(&db)?;
         self.begin_tx(&db)?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+fn busy_conflict(batch: &WriteBatch) -> Error {
+    match batch.ops.iter().find(|op| matches!(op, BatchOp::Expect(..))) {
+        Some(BatchOp::Expect(ver, key, _)) => Error::Conflict { ver: *ver, key: key.clone() },
+        _ => Error::ImplError("database is locked".to_owned()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_u16_store() {
+        let path = std::env::temp_dir().join(format!("sqlite-{}-u16.db", std::process::id()));
+        {
+            // Laid out as stores with 16 bit versions were
+            let db = Connection::open(&path).unwrap();
+            db.execute("create table data(ver int, key text, value blob, primary key(ver, key))", NO_PARAMS).unwrap();
+            db.execute("insert into data values(?,?,?)", params![u16::MAX, &b"a"[..], &b"one"[..]]).unwrap();
+        }
+
+        // Nothing to rewrite, opening again sees the same records
+        for _ in 0..2 {
+            let kv = SqliteDB::new(&path).unwrap();
+            assert_eq!(kv.get(u16::MAX as u64, b"a").unwrap(), Some(b"one".to_vec()));
+            assert_eq!(kv.scan(u16::MAX as u64, &Scan::all()).unwrap().len(), 1);
+        }
+
+        let kv = SqliteDB::new(&path).unwrap();
+        kv.put(1 << 40, b"a", b"two").unwrap();
+        assert_eq!(kv.get(1 << 40, b"a").unwrap(), Some(b"two".to_vec()));
+        assert!(kv.put(u64::MAX, b"a", b"three").is_err());
+
+        drop(kv);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn expectations_across_connections() {
+        let path = std::env::temp_dir().join(format!("sqlite-{}-cas.db", std::process::id()));
+        let a = SqliteDB::new(&path).unwrap();
+        let b = SqliteDB::new(&path).unwrap();
+
+        // Both hold a read transaction, as open stores do
+        assert_eq!(a.get(0, b"lock").unwrap(), None);
+        assert_eq!(b.get(0, b"lock").unwrap(), None);
+
+        let mut batch = WriteBatch::new();
+        batch.expect(0, b"lock", None);
+        batch.put(0, b"lock", b"a");
+        a.write(batch.clone()).unwrap();
+        a.sync().unwrap();
+
+        // b's snapshot still has no lock record, the check must not use it
+        assert!(matches!(b.write(batch), Err(Error::Conflict{..})));
+        assert_eq!(b.get(0, b"lock").unwrap(), Some(b"a".to_vec()));
+
+        // A connection in the middle of writing past the busy timeout
+        a.put(1, b"k", b"v").unwrap();
+        b.db.lock().busy_timeout(std::time::Duration::from_millis(10)).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.expect(0, b"lock", Some(b"a"));
+        batch.delete(0, b"lock");
+        match b.write(batch.clone()) {
+            Err(Error::Conflict { ver, key }) => assert_eq!((ver, key), (0, b"lock".to_vec())),
+            r => panic!("unexpected {:?}", r),
+        }
+
+        a.sync().unwrap();
+        b.write(batch).unwrap();
+        assert_eq!(a.get(0, b"lock").unwrap(), None);
+        assert_eq!(b.get(1, b"k").unwrap(), Some(b"v".to_vec()));
+
+        drop((a, b));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
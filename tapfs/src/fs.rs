@@ -4,14 +4,14 @@ use std::path::{Path, PathBuf};
 use vstore::{Tree, VStore};
 use parking_lot::{Mutex, RwLock};
 
-use keyvalue::{compress::CompressKV, sqlite::SqliteDB, KeyValue};
+use keyvalue::{compress::CompressKV, sqlite::SqliteDB, memory::MemoryKV, KeyValue};
 use crate::state::State;
 use crate::Error;
 
 #[derive(Clone)]
 pub struct FileSystemArgs {
     pub read_only: bool,
-    pub version: u16,
+    pub version: u64,
 }
 
 pub struct FileSystem {
@@ -37,6 +37,15 @@ impl FileSystem {
         Ok(fs)
     }
 
+    /// File system on a fresh in-memory store, for tests and throwaway
+    /// mounts. Nothing is kept once it is dropped.
+    pub fn scratch(args: &FileSystemArgs) -> Result<Self, Error> {
+        let kv: Arc<Box<dyn KeyValue>> = Arc::new(CompressKV::new(Arc::new(MemoryKV::new_box())));
+        let v = VStore::create(kv)?;
+
+        FileSystem::create(args, Arc::new(v))
+    }
+
     fn init(s: &mut State) -> Result<(), Error> {
         s.write_ino_num(0)?;
 
@@ -6,7 +6,7 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, PartialEq, Deserialize, Serialize)]
 pub struct KeyPack {
     version: u32, 
-    pub map: HashMap<Vec<u8>, u16>,
+    pub map: HashMap<Vec<u8>, u64>,
 }
 
 impl KeyPack {
@@ -17,11 +17,11 @@ impl KeyPack {
         }
     }
 
-    pub fn put(&mut self, ver: u16, key: &[u8]) {
+    pub fn put(&mut self, ver: u64, key: &[u8]) {
         self.map.insert(Vec::from(key), ver);
     }
 
-    pub fn get<'a>(&'a self, key: &[u8]) -> Option<u16> {
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<u64> {
         self.map.get(key).map(|v| *v)
     }
 
@@ -37,10 +37,10 @@ impl KeyPack {
 }
 
 
-#[derive(Debug, PartialEq, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct Pack {
     version: u32, 
-    pub map: HashMap<Vec<u8>, (u16, Vec<u8>)>,
+    pub map: HashMap<Vec<u8>, (u64, Vec<u8>)>,
 }
 
 
@@ -52,11 +52,11 @@ impl Pack {
         }
     }
 
-    pub fn put(&mut self, ver: u16, key: &[u8], val: Vec<u8>) {
+    pub fn put(&mut self, ver: u64, key: &[u8], val: Vec<u8>) {
         self.map.insert(key.to_owned(), (ver, val));
     }
 
-    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a (u16, Vec<u8>)> {
+    pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a (u64, Vec<u8>)> {
         self.map.get(key)
     }
 
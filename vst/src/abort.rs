@@ -0,0 +1,31 @@
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct AbortCmdArgs {
+    pub store_path: String,
+    /// Branch whose open version is thrown away
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
+}
+
+pub fn cmd(args: AbortCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    let open_ver = v.branches().into_iter()
+        .find(|(name, _)| *name == args.branch)
+        .map(|(_, b)| b.open_ver)
+        .ok_or(vstore::Error::BranchNotFound(args.branch.clone()))?;
+
+    if open_ver == 0 {
+        println!("no open version on {}", args.branch);
+        return Ok(());
+    }
+
+    let t = v.writable_branch(&args.branch)?;
+    let stats = v.abort(t)?;
+    println!("aborted version {} records: {} bytes: {}", open_ver, stats.records, stats.bytes);
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct BranchCmdArgs {
+    pub store_path: String,
+    #[structopt(subcommand)]
+    pub op: BranchOp,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum BranchOp {
+    List,
+    Create {
+        name: String,
+        /// Version number or tag
+        ver: String,
+    },
+    Delete {
+        name: String,
+    },
+}
+
+pub fn cmd(args: BranchCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    match args.op {
+        BranchOp::List => {
+            for (name, b) in v.branches() {
+                println!("{} head: {} open: {}", name, b.head_ver, b.open_ver);
+            }
+        },
+        BranchOp::Create{name, ver} => {
+            let ver = v.resolve_version(&ver)?;
+            v.create_branch(&name, ver)?;
+        },
+        BranchOp::Delete{name} => {
+            v.delete_branch(&name)?;
+        },
+    }
+
+    Ok(())
+}
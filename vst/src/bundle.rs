@@ -0,0 +1,68 @@
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+use vstore::Bundle;
+
+#[derive(Debug, StructOpt)]
+pub struct BundleCmdArgs {
+    pub store_path: String,
+    #[structopt(subcommand)]
+    pub op: BundleOp,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum BundleOp {
+    Export {
+        file: String,
+        /// Branch whose last commit is bundled
+        #[structopt(short, long, default_value = "main")]
+        branch: String,
+        /// Version number or tag to bundle up to instead of the branch
+        #[structopt(long)]
+        tip: Option<String>,
+        /// Version number or tag the receiving store already has
+        #[structopt(long)]
+        base: Option<String>,
+    },
+    Import {
+        file: String,
+        /// Branch moved to the bundle's tip when it is a descendant
+        #[structopt(short, long, default_value = "main")]
+        branch: String,
+    },
+}
+
+pub fn cmd(args: BundleCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    match args.op {
+        BundleOp::Export{file, branch, tip, base} => {
+            let tip = match tip {
+                Some(spec) => v.resolve_version(&spec)?,
+                None => v.commit_state().committed_head(&branch)?,
+            };
+            let base = match base {
+                Some(spec) => v.resolve_version(&spec)?,
+                None => 0,
+            };
+
+            let b = v.export_bundle(base, tip)?;
+            b.write_to(&mut BufWriter::new(File::create(&file)?))?;
+            println!("commits: {} bytes: {}", b.commits.len(), b.size());
+        },
+        BundleOp::Import{file, branch} => {
+            let b = Bundle::read_from(&mut BufReader::new(File::open(&file)?))?;
+            let stats = v.import_bundle(&b)?;
+            println!("commits: {} packs: {} bytes: {}", stats.commits, stats.packs, stats.bytes);
+
+            if let Err(e) = v.fast_forward(&branch, b.tip) {
+                println!("{} not moved to {}: {}", branch, b.tip, e);
+            }
+        },
+    }
+
+    Ok(())
+}
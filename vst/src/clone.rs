@@ -0,0 +1,28 @@
+
+use crate::{store_kv, with_remote};
+use structopt::StructOpt;
+use anyhow::Error;
+use vstore::VStore;
+
+#[derive(Debug, StructOpt)]
+pub struct CloneCmdArgs {
+    /// Store path, tcp://host:port, or with --exec a command running vst serve
+    pub remote: String,
+    pub store_path: String,
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
+    /// Copy only this many of the latest commits
+    #[structopt(short, long)]
+    pub depth: Option<usize>,
+    #[structopt(short, long)]
+    pub exec: bool,
+}
+
+pub fn cmd(args: CloneCmdArgs) -> Result<(), Error> {
+    let kv = store_kv(&args.store_path)?;
+
+    let v = with_remote(&args.remote, args.exec, |r| Ok(VStore::clone_from(kv, r, &args.branch, args.depth)?))?;
+    println!("commits: {}", v.commit_state().versions().len());
+
+    Ok(())
+}
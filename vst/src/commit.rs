@@ -1,19 +1,40 @@
 
 use crate::open_vstore;
 use structopt::StructOpt;
-use anyhow::Error;
+use anyhow::{anyhow, Error};
+use vstore::CommitMeta;
 
 #[derive(Debug, StructOpt)]
 pub struct CommitCmdArgs {
     pub store_path: String,
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
+    #[structopt(short, long, default_value = "")]
+    pub message: String,
+    /// Defaults to $USER
+    #[structopt(short, long)]
+    pub author: Option<String>,
+    /// Extra property as key=value, may be repeated
+    #[structopt(short, long = "prop")]
+    pub props: Vec<String>,
 }
 
 pub fn cmd_commit(args: CommitCmdArgs) -> Result<(), Error> {
     let v = open_vstore(&args.store_path)?;
 
-    let t = v.writable()?;
+    let author = args.author.clone()
+        .or_else(|| std::env::var("USER").ok())
+        .unwrap_or_default();
 
-    v.commit(t)?;
+    let mut meta = CommitMeta::new(&args.message).author(&author);
+    for prop in args.props.iter() {
+        let (k, val) = prop.split_at(prop.find('=').ok_or(anyhow!("property {} is not key=value", prop))?);
+        meta = meta.prop(k, &val[1..]);
+    }
+
+    let t = v.writable_branch(&args.branch)?;
+
+    v.commit_with(t, meta)?;
 
     Ok(())
-}
\ No newline at end of file
+}
@@ -6,13 +6,15 @@ use anyhow::Error;
 #[derive(Debug, StructOpt)]
 pub struct DelCmdArgs {
     pub store_path: String,
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
     pub key: String,
 }
 
 pub fn cmd(args: DelCmdArgs) -> Result<(), Error> {
     let v = open_vstore(&args.store_path)?;
 
-    let t = v.writable()?;
+    let t = v.writable_branch(&args.branch)?;
     t.delete(args.key.as_bytes())?;
 
     v.sync_tree(&t)?;
@@ -7,14 +7,19 @@ use vstore::diff::DiffType;
 #[derive(Debug, StructOpt)]
 pub struct DiffCmdArgs {
     pub store_path: String,
-    pub aver: u16,
-    pub bver: u16,
+    /// Version number or tag
+    pub aver: String,
+    /// Version number or tag
+    pub bver: String,
 }
 
 pub fn cmd(args: DiffCmdArgs) -> Result<(), Error> {
     let v = open_vstore(&args.store_path)?;
 
-    let mut d = v.diff(args.aver, args.bver)?;
+    let aver = v.resolve_version(&args.aver)?;
+    let bver = v.resolve_version(&args.bver)?;
+
+    let mut d = v.diff(aver, bver)?;
 
     while let Some(item_vec) = d.next()? {
         for item in item_vec {
@@ -0,0 +1,40 @@
+
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::{anyhow, Error};
+use vstore::RetentionPolicy;
+
+#[derive(Debug, StructOpt)]
+pub struct GcCmdArgs {
+    pub store_path: String,
+    /// Commits kept on each branch counting back from its head
+    #[structopt(short = "n", long)]
+    pub keep_last: Option<usize>,
+    /// Keep commits younger than this many seconds
+    #[structopt(long)]
+    pub max_age: Option<u64>,
+    /// Drop tagged commits too, removing their tags
+    #[structopt(long)]
+    pub drop_tagged: bool,
+}
+
+pub fn cmd(args: GcCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    let mut policy = RetentionPolicy::default().keep_tagged(!args.drop_tagged);
+    if let Some(n) = args.keep_last {
+        policy = policy.keep_last(n);
+    }
+    if let Some(secs) = args.max_age {
+        policy = policy.max_age(secs);
+    }
+
+    if !policy.limits() {
+        return Err(anyhow!("nothing would expire, give --keep-last or --max-age"));
+    }
+
+    let stats = v.gc(&policy)?;
+    println!("versions: {} records: {} bytes: {}", stats.versions, stats.records, stats.bytes);
+
+    Ok(())
+}
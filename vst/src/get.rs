@@ -6,14 +6,16 @@ use anyhow::Error;
 #[derive(Debug, StructOpt)]
 pub struct GetCmdArgs {
     pub store_path: String,
-    pub ver: u16,
+    /// Version number or tag
+    pub ver: String,
     pub key: String,
 }
 
 pub fn cmd_get(args: GetCmdArgs) -> Result<(), Error> {
     let v = open_vstore(&args.store_path)?;
 
-    let t = v.read_only(args.ver)?;
+    let ver = v.resolve_version(&args.ver)?;
+    let t = v.read_only(ver)?;
 
     match t.get(args.key.as_bytes())? {
         None => println!("key not found"),
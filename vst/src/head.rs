@@ -14,5 +14,9 @@ pub fn cmd(args: HeadCmdArgs) -> Result<(), Error> {
     println!("head version: {}", cs.head_version());
     println!("open version: {}", cs.open_version());
 
+    if let Some(l) = v.writer_lock()? {
+        println!("locked by pid {} since {}", l.pid, l.acquired);
+    }
+
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,39 @@
+
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+use vstore::KeyChange;
+
+#[derive(Debug, StructOpt)]
+pub struct HistoryCmdArgs {
+    pub store_path: String,
+    pub key: String,
+    /// Branch to walk from its last commit
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
+    /// Version number or tag to walk from instead of a branch head
+    #[structopt(short, long)]
+    pub from: Option<String>,
+}
+
+pub fn cmd(args: HistoryCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    let from = match &args.from {
+        Some(spec) => v.resolve_version(spec)?,
+        None => v.commit_state().committed_head(&args.branch)?,
+    };
+
+    for e in v.key_history(args.key.as_bytes(), from)? {
+        let change = match &e.change {
+            KeyChange::Created(val) => format!("created {}", val.len()),
+            KeyChange::Modified(val) => format!("modified {}", val.len()),
+            KeyChange::Deleted => "deleted".to_owned(),
+        };
+
+        println!("version {} {} author: {} timestamp: {}",
+            e.commit.ver, change, e.commit.meta.author, e.commit.meta.timestamp);
+    }
+
+    Ok(())
+}
@@ -1,9 +1,13 @@
-
 use crate::create_vstore;
 use anyhow::Error;
+use vstore::StoreOptions;
 
+pub fn cmd_init(path: String, prefix_bits: Option<usize>) -> Result<(), Error> {
+    let mut opts = StoreOptions::default();
+    if let Some(bits) = prefix_bits {
+        opts = opts.prefix_bits(bits);
+    }
 
-pub fn cmd_init(path: String) -> Result<(), Error> {
-    create_vstore(&path)?;
+    create_vstore(&path, opts)?;
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,56 @@
+
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct ListCmdArgs {
+    pub store_path: String,
+    /// Version number or tag
+    pub ver: String,
+    /// Only keys starting with this
+    #[structopt(short, long)]
+    pub prefix: Option<String>,
+    /// List in key order, holding every key in memory
+    #[structopt(short, long)]
+    pub sorted: bool,
+    /// Print counts instead of keys
+    #[structopt(long)]
+    pub summary: bool,
+}
+
+pub fn cmd(args: ListCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    let ver = v.resolve_version(&args.ver)?;
+    let t = v.read_only(ver)?;
+
+    let it = match &args.prefix {
+        Some(prefix) => t.iter_prefix(prefix.as_bytes()),
+        None => t.iter(),
+    };
+
+    if args.summary {
+        let s = it.summary()?;
+        println!("keys: {} bytes: {} packs: {}", s.keys, s.bytes, s.packs);
+
+        let c = v.cache_stats();
+        println!("pack cache hits: {} misses: {} entries: {} bytes: {}", c.hits, c.misses, c.entries, c.bytes);
+        return Ok(());
+    }
+
+    let print = |(k, val): (Vec<u8>, Vec<u8>)| {
+        let k = std::str::from_utf8(k.as_slice()).unwrap_or("binary");
+        println!("{} {}", k, val.len());
+    };
+
+    if args.sorted {
+        it.sorted()?.for_each(print);
+    }else{
+        for item in it {
+            print(item?);
+        }
+    }
+
+    Ok(())
+}
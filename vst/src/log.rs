@@ -0,0 +1,52 @@
+
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct LogCmdArgs {
+    pub store_path: String,
+    /// Branch to walk from its head
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
+    /// Version number or tag to walk from instead of a branch head
+    #[structopt(short, long)]
+    pub from: Option<String>,
+}
+
+pub fn cmd(args: LogCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+    let cs = v.commit_state();
+
+    let mut ver = match &args.from {
+        Some(spec) => v.resolve_version(spec)?,
+        None => v.branches().into_iter()
+            .find(|(name, _)| *name == args.branch)
+            .map(|(_, b)| b.head_ver)
+            .ok_or(vstore::Error::BranchNotFound(args.branch.clone()))?,
+    };
+
+    while ver != 0 {
+        let c = match cs.get_commit(ver) {
+            Some(c) => c,
+            None => break,
+        };
+
+        let open = if cs.is_open(c.ver) { " (open)" } else { "" };
+        println!("version {}{}", c.ver, open);
+        println!("author: {}", c.meta.author);
+        println!("timestamp: {}", c.meta.timestamp);
+        for (k, val) in c.meta.props.iter() {
+            println!("{}: {}", k, val);
+        }
+        println!("\n    {}\n", c.meta.message);
+
+        ver = c.prev_ver;
+    }
+
+    if ver != 0 && ver == cs.shallow_version() {
+        println!("shallow clone, versions up to {} not present", ver);
+    }
+
+    Ok(())
+}
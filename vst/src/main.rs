@@ -6,6 +6,23 @@ mod commit;
 mod head;
 mod diff;
 mod delete;
+mod branch;
+mod tag;
+mod log;
+mod merge;
+mod gc;
+mod squash;
+mod list;
+mod history;
+mod bundle;
+mod serve;
+mod push;
+mod pull;
+mod clone;
+mod reshard;
+mod unlock;
+mod abort;
+mod revert;
 
 use util::*;
 
@@ -17,6 +34,9 @@ use anyhow::Error;
 enum Cli {
     Init {
         path: String,
+        /// Spread keys over 2^N partitions
+        #[structopt(long)]
+        prefix_bits: Option<usize>,
     },
     Get(get::GetCmdArgs),
     Head(head::HeadCmdArgs),
@@ -24,6 +44,23 @@ enum Cli {
     Commit(commit::CommitCmdArgs),
     Diff(diff::DiffCmdArgs),
     Delete(delete::DelCmdArgs),
+    Branch(branch::BranchCmdArgs),
+    Tag(tag::TagCmdArgs),
+    Log(log::LogCmdArgs),
+    Merge(merge::MergeCmdArgs),
+    Gc(gc::GcCmdArgs),
+    Squash(squash::SquashCmdArgs),
+    List(list::ListCmdArgs),
+    History(history::HistoryCmdArgs),
+    Bundle(bundle::BundleCmdArgs),
+    Serve(serve::ServeCmdArgs),
+    Push(push::PushCmdArgs),
+    Pull(pull::PullCmdArgs),
+    Clone(clone::CloneCmdArgs),
+    Reshard(reshard::ReshardCmdArgs),
+    Unlock(unlock::UnlockCmdArgs),
+    Abort(abort::AbortCmdArgs),
+    Revert(revert::RevertCmdArgs),
 }
 
 
@@ -32,8 +69,8 @@ fn main() -> Result<(), Error> {
 
     let opt = Cli::from_args();
     match opt {
-        Cli::Init{path} => {
-            init::cmd_init(path)?;
+        Cli::Init{path, prefix_bits} => {
+            init::cmd_init(path, prefix_bits)?;
         },
         Cli::Get(args) => {
             get::cmd_get(args)?;
@@ -53,6 +90,57 @@ fn main() -> Result<(), Error> {
         Cli::Delete(args) => {
             delete::cmd(args)?;
         },
+        Cli::Branch(args) => {
+            branch::cmd(args)?;
+        },
+        Cli::Tag(args) => {
+            tag::cmd(args)?;
+        },
+        Cli::Log(args) => {
+            log::cmd(args)?;
+        },
+        Cli::Merge(args) => {
+            merge::cmd(args)?;
+        },
+        Cli::Gc(args) => {
+            gc::cmd(args)?;
+        },
+        Cli::Squash(args) => {
+            squash::cmd(args)?;
+        },
+        Cli::List(args) => {
+            list::cmd(args)?;
+        },
+        Cli::History(args) => {
+            history::cmd(args)?;
+        },
+        Cli::Bundle(args) => {
+            bundle::cmd(args)?;
+        },
+        Cli::Serve(args) => {
+            serve::cmd(args)?;
+        },
+        Cli::Push(args) => {
+            push::cmd(args)?;
+        },
+        Cli::Pull(args) => {
+            pull::cmd(args)?;
+        },
+        Cli::Clone(args) => {
+            clone::cmd(args)?;
+        },
+        Cli::Reshard(args) => {
+            reshard::cmd(args)?;
+        },
+        Cli::Unlock(args) => {
+            unlock::cmd(args)?;
+        },
+        Cli::Abort(args) => {
+            abort::cmd(args)?;
+        },
+        Cli::Revert(args) => {
+            revert::cmd(args)?;
+        },
     }
     Ok(())
 }
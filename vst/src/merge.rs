@@ -0,0 +1,58 @@
+
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::{anyhow, Error};
+use vstore::{CommitMeta, Resolver, TakeOurs, TakeTheirs, NoConflicts};
+
+#[derive(Debug, StructOpt)]
+pub struct MergeCmdArgs {
+    pub store_path: String,
+    /// Branch, version number or tag to merge
+    pub theirs: String,
+    /// Branch receiving the merge
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
+    /// Version number or tag, defaults to the common ancestor
+    #[structopt(long)]
+    pub base: Option<String>,
+    /// How to settle conflicting keys: fail, ours or theirs
+    #[structopt(short, long, default_value = "fail")]
+    pub strategy: String,
+}
+
+pub fn cmd(args: MergeCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+    let cs = v.commit_state();
+
+    let theirs = match v.branches().iter().any(|(name, _)| *name == args.theirs) {
+        true => cs.committed_head(&args.theirs)?,
+        false => v.resolve_version(&args.theirs)?,
+    };
+
+    let ours = cs.committed_head(&args.branch)?;
+
+    let base = match &args.base {
+        Some(spec) => v.resolve_version(spec)?,
+        None => cs.common_ancestor(ours, theirs)
+            .ok_or(anyhow!("no common ancestor of {} and {}", ours, theirs))?,
+    };
+
+    let mut resolver: Box<dyn Resolver> = match args.strategy.as_str() {
+        "fail" => Box::new(NoConflicts),
+        "ours" => Box::new(TakeOurs),
+        "theirs" => Box::new(TakeTheirs),
+        s => return Err(anyhow!("unknown strategy {}", s)),
+    };
+
+    let t = v.writable_branch(&args.branch)?;
+    let stats = v.merge(&t, base, theirs, resolver.as_mut())?;
+
+    let meta = CommitMeta::new(&format!("merge {}", args.theirs))
+        .prop("merge", &theirs.to_string())
+        .prop("merge-base", &base.to_string());
+    v.commit_with(t, meta)?;
+
+    println!("applied: {} conflicts: {} partitions: {}", stats.applied, stats.conflicts, stats.parts);
+
+    Ok(())
+}
@@ -0,0 +1,24 @@
+
+use crate::{open_vstore, with_remote};
+use structopt::StructOpt;
+use anyhow::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct PushCmdArgs {
+    pub store_path: String,
+    /// Store path, tcp://host:port, or with --exec a command running vst serve
+    pub remote: String,
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
+    #[structopt(short, long)]
+    pub exec: bool,
+}
+
+pub fn cmd(args: PushCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    let stats = with_remote(&args.remote, args.exec, |r| Ok(v.push(r, &args.branch)?))?;
+    println!("commits: {} packs: {} bytes: {}", stats.commits, stats.packs, stats.bytes);
+
+    Ok(())
+}
@@ -6,6 +6,8 @@ use anyhow::Error;
 #[derive(Debug, StructOpt)]
 pub struct PutCmdArgs {
     pub store_path: String,
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
     pub key: String,
     pub value: String,
 }
@@ -13,7 +15,7 @@ pub struct PutCmdArgs {
 pub fn cmd_put(args: PutCmdArgs) -> Result<(), Error> {
     let v = open_vstore(&args.store_path)?;
 
-    let t = v.writable()?;
+    let t = v.writable_branch(&args.branch)?;
     t.put(args.key.as_bytes(), args.value.as_bytes())?;
 
     v.sync_tree(&t)?;
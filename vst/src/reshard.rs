@@ -0,0 +1,30 @@
+
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+use vstore::CommitMeta;
+
+#[derive(Debug, StructOpt)]
+pub struct ReshardCmdArgs {
+    pub store_path: String,
+    /// Spread keys over 2^N partitions, more than the branch uses now
+    pub prefix_bits: usize,
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
+}
+
+pub fn cmd(args: ReshardCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    let mut t = v.writable_branch(&args.branch)?;
+    v.reshard(&mut t, args.prefix_bits)?;
+
+    let summary = t.summary()?;
+    let meta = CommitMeta::new(&format!("reshard to {} bits", args.prefix_bits))
+        .prop("prefix-bits", &args.prefix_bits.to_string());
+    v.commit_with(t, meta)?;
+
+    println!("keys: {} packs: {}", summary.keys, summary.packs);
+
+    Ok(())
+}
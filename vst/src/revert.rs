@@ -0,0 +1,23 @@
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct RevertCmdArgs {
+    pub store_path: String,
+    /// Version number or tag whose contents are restored
+    pub ver: String,
+    /// Branch receiving the new commit
+    #[structopt(short, long, default_value = "main")]
+    pub branch: String,
+}
+
+pub fn cmd(args: RevertCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    let ver = v.resolve_version(&args.ver)?;
+    let new_ver = v.revert_branch(&args.branch, ver)?;
+    println!("version {} restores {}", new_ver, ver);
+
+    Ok(())
+}
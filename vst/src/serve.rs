@@ -0,0 +1,59 @@
+use std::io;
+use std::net::{TcpListener, SocketAddr, Ipv4Addr};
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::{anyhow, Error};
+use log::{info, warn};
+use vstore::sync::serve;
+
+#[derive(Debug, StructOpt)]
+pub struct ServeCmdArgs {
+    pub store_path: String,
+    /// Accept connections on this port of localhost, or on a host:port
+    /// address, instead of using stdin and stdout. Connections are not
+    /// authenticated: anyone who can connect can read the whole store and
+    /// push to its branches.
+    #[structopt(short, long)]
+    pub listen: Option<String>,
+    /// Allow listening on an address other hosts can reach
+    #[structopt(long)]
+    pub allow_remote: bool,
+}
+
+/// A bare port binds to localhost only
+fn listen_addr(listen: &str, allow_remote: bool) -> Result<SocketAddr, Error> {
+    let addr = match listen.parse::<u16>() {
+        Ok(port) => SocketAddr::from((Ipv4Addr::LOCALHOST, port)),
+        Err(_) => listen.parse::<SocketAddr>()?,
+    };
+
+    if !addr.ip().is_loopback() && !allow_remote {
+        return Err(anyhow!("{} is reachable from other hosts, pass --allow-remote to serve on it", addr));
+    }
+
+    Ok(addr)
+}
+
+pub fn cmd(args: ServeCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    match &args.listen {
+        Some(listen) => {
+            let addr = listen_addr(listen, args.allow_remote)?;
+            let l = TcpListener::bind(addr)?;
+            info!("serving {} on {}", args.store_path, addr);
+
+            for s in l.incoming() {
+                let s = s?;
+                if let Err(e) = serve(&v, &mut s.try_clone()?, &mut &s) {
+                    warn!("connection failed: {}", e);
+                }
+            }
+        },
+        None => {
+            serve(&v, &mut io::stdin(), &mut io::stdout())?;
+        },
+    }
+
+    Ok(())
+}
@@ -0,0 +1,25 @@
+
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct SquashCmdArgs {
+    pub store_path: String,
+    /// Version number or tag kept as the parent
+    pub from: String,
+    /// Version number or tag, its ancestors back to `from` are dropped
+    pub to: String,
+}
+
+pub fn cmd(args: SquashCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    let from = v.resolve_version(&args.from)?;
+    let to = v.resolve_version(&args.to)?;
+
+    let stats = v.squash(from, to)?;
+    println!("versions: {} records: {} bytes: {}", stats.versions, stats.records, stats.bytes);
+
+    Ok(())
+}
@@ -0,0 +1,45 @@
+
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct TagCmdArgs {
+    pub store_path: String,
+    #[structopt(subcommand)]
+    pub op: TagOp,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum TagOp {
+    List,
+    Create {
+        name: String,
+        /// Version number or tag
+        ver: String,
+    },
+    Delete {
+        name: String,
+    },
+}
+
+pub fn cmd(args: TagCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    match args.op {
+        TagOp::List => {
+            for (name, ver) in v.tags() {
+                println!("{} {}", name, ver);
+            }
+        },
+        TagOp::Create{name, ver} => {
+            let ver = v.resolve_version(&ver)?;
+            v.create_tag(&name, ver)?;
+        },
+        TagOp::Delete{name} => {
+            v.delete_tag(&name)?;
+        },
+    }
+
+    Ok(())
+}
@@ -0,0 +1,20 @@
+use crate::open_vstore;
+use structopt::StructOpt;
+use anyhow::Error;
+
+#[derive(Debug, StructOpt)]
+pub struct UnlockCmdArgs {
+    pub store_path: String,
+}
+
+/// Breaks a writer lock left behind by a process that died holding it.
+pub fn cmd(args: UnlockCmdArgs) -> Result<(), Error> {
+    let v = open_vstore(&args.store_path)?;
+
+    match v.break_lock()? {
+        Some(l) => println!("removed lock of pid {} since {}", l.pid, l.acquired),
+        None => println!("not locked"),
+    }
+
+    Ok(())
+}
@@ -1,30 +1,89 @@
 use std::sync::Arc;
 use std::path::Path;
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
 
-use vstore::VStore;
-use keyvalue::{sqlite::SqliteDB, compress::CompressKV, KeyValue};
+use vstore::{VStore, StoreOptions};
+use vstore::sync::{Remote, StreamRemote};
+use keyvalue::{sqlite::SqliteDB, memory::MemoryKV, compress::CompressKV, encrypt::EncryptKV, checksum::ChecksumKV, Scan, KeyValue};
 
-use anyhow::Error;
+use anyhow::{anyhow, Error};
 
 
 
+/// Store paths name an SQLite database, or with a `mem:` prefix a
+/// snapshot file of an in-memory store, rewritten on every sync.
 pub fn create_kv(p: &str) -> Result<Box<dyn KeyValue>, Error> {
-    let kv = SqliteDB::new_box(&Path::new(p))?;
+    let kv = match p.strip_prefix("mem:") {
+        Some(path) => MemoryKV::with_file_box(Path::new(path))?,
+        None => SqliteDB::new_box(Path::new(p))?,
+    };
+
+    // New stores get checksums, stores written without them are read as they are
+    let kv = match ChecksumKV::is_checksummed(&*kv)? {
+        true => ChecksumKV::new_box(Arc::new(kv)),
+        false if kv.scan(0, &Scan::all().limit(1))?.is_empty() => ChecksumKV::create_box(Arc::new(kv))?,
+        false => kv,
+    };
+
+    // Values are compressed before encryption, the cipher text is not compressible
+    let kv = match std::env::var("VST_PASSPHRASE") {
+        Ok(pass) => EncryptKV::new_box(Arc::new(kv), pass.as_bytes())?,
+        Err(_) if EncryptKV::is_encrypted(&*kv)? => return Err(anyhow!("{} is encrypted, set VST_PASSPHRASE", p)),
+        Err(_) => kv,
+    };
+
     Ok(kv)
 }
 
-pub fn create_vstore(p: &str) -> Result<VStore, Error> {
+pub fn store_kv(p: &str) -> Result<Arc<Box<dyn KeyValue>>, Error> {
     let kv = create_kv(p)?;
     let kv = CompressKV::new(Arc::new(kv));
-    let v = VStore::create(Arc::new(kv))?;
+
+    Ok(Arc::new(kv))
+}
+
+pub fn create_vstore(p: &str, opts: StoreOptions) -> Result<VStore, Error> {
+    let v = VStore::create_with(store_kv(p)?, opts)?;
 
     Ok(v)
 }
 
 pub fn open_vstore(p: &str) -> Result<VStore, Error> {
-    let kv = create_kv(p)?;
-    let kv = CompressKV::new(Arc::new(kv));
-    let v = VStore::open(Arc::new(kv))?;
+    let v = VStore::open(store_kv(p)?)?;
 
     Ok(v)
-}
\ No newline at end of file
+}
+
+/// Runs `f` against a remote given as a store path, a `tcp://host:port`
+/// address of `vst serve --listen`, or with `exec` a shell command whose
+/// stdin and stdout speak to `vst serve`.
+pub fn with_remote<T>(remote: &str, exec: bool, f: impl FnOnce(&mut dyn Remote) -> Result<T, Error>) -> Result<T, Error> {
+    if exec {
+        let mut child = Command::new("sh").arg("-c").arg(remote)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+
+        let r = child.stdout.take().ok_or(anyhow!("no stdout for {}", remote))?;
+        let w = child.stdin.take().ok_or(anyhow!("no stdin for {}", remote))?;
+
+        // Dropping the remote closes its stdin, which ends the server
+        let res = f(&mut StreamRemote::new(r, w));
+
+        let status = child.wait()?;
+        if res.is_ok() && !status.success() {
+            return Err(anyhow!("{} exited with {}", remote, status));
+        }
+
+        return res;
+    }
+
+    if let Some(addr) = remote.strip_prefix("tcp://") {
+        let s = TcpStream::connect(addr)?;
+        return f(&mut StreamRemote::new(s.try_clone()?, s));
+    }
+
+    let mut v = open_vstore(remote)?;
+    f(&mut v)
+}
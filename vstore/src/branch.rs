@@ -0,0 +1,26 @@
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use crate::Error;
+
+/// Name under which the store's original line of history is listed. It
+/// cannot be created or deleted.
+pub const MAIN_BRANCH: &str = "main";
+
+/// Head and open version of a line of history, zero when unset.
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct Branch {
+    pub head_ver: u64,
+    pub open_ver: u64,
+}
+
+pub (crate) type BranchTable = BTreeMap<String, Branch>;
+
+pub (crate) fn table_to_vec(t: &BranchTable) -> Result<Vec<u8>, Error> {
+    let buf = rmp_serde::to_vec(t)?;
+    Ok(buf)
+}
+
+pub (crate) fn table_from_buf(buf: &[u8]) -> Result<BranchTable, Error> {
+    let t = rmp_serde::from_read_ref(buf)?;
+    Ok(t)
+}
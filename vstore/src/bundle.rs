@@ -0,0 +1,88 @@
+use std::io::{Read, Write};
+use std::convert::TryInto;
+use serde::{Deserialize, Serialize};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::commit::Commit;
+use crate::Error;
+
+const BUNDLE_MAGIC: &[u8; 8] = b"VSTBNDL1";
+
+#[derive(Debug, Deserialize, Serialize)]
+pub (crate) struct BundleIndex {
+    pub ver: u64,
+    #[serde(with = "serde_bytes")]
+    pub buf: Vec<u8>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub (crate) struct BundlePack {
+    pub ver: u64,
+    pub part: u32,
+    #[serde(with = "serde_bytes")]
+    pub buf: Vec<u8>,
+}
+
+/// Commits from `base` (exclusive, 0 for the root) up to `tip` with their
+/// indexes and the packs they read that `base` does not. Written as a magic,
+/// an xxh3 checksum of the body and the msgpack body.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Bundle {
+    pub base: u64,
+    pub tip: u64,
+    /// Oldest first
+    pub commits: Vec<Commit>,
+    pub (crate) indexes: Vec<BundleIndex>,
+    pub (crate) packs: Vec<BundlePack>,
+
+    /// Carries every pack its commits read, so a store without `base` can
+    /// import it as a shallow clone.
+    #[serde(default)]
+    pub shallow: bool,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub struct BundleStats {
+    /// Commits new to the importing store
+    pub commits: u64,
+    pub packs: u64,
+    pub bytes: u64,
+}
+
+impl Bundle {
+    pub fn write_to(&self, w: &mut dyn Write) -> Result<(), Error> {
+        let body = rmp_serde::to_vec(self)?;
+
+        w.write_all(&BUNDLE_MAGIC[..])?;
+        w.write_all(&xxh3_64(&body).to_be_bytes())?;
+        w.write_all(&body)?;
+        w.flush()?;
+
+        Ok(())
+    }
+
+    pub fn read_from(r: &mut dyn Read) -> Result<Bundle, Error> {
+        let mut buf = Vec::new();
+        r.read_to_end(&mut buf)?;
+
+        if buf.len() < 16 || buf[..8] != BUNDLE_MAGIC[..] {
+            return Err(Error::BundleInvalid("not a bundle".to_owned()));
+        }
+
+        let sum = u64::from_be_bytes(buf[8..16].try_into().unwrap());
+        let body = &buf[16..];
+        if xxh3_64(body) != sum {
+            return Err(Error::BundleInvalid("checksum mismatch".to_owned()));
+        }
+
+        let b = rmp_serde::from_read_ref(body)?;
+        Ok(b)
+    }
+
+    /// Total size of the index and pack records carried.
+    pub fn size(&self) -> u64 {
+        let idx: usize = self.indexes.iter().map(|i| i.buf.len()).sum();
+        let packs: usize = self.packs.iter().map(|p| p.buf.len()).sum();
+        (idx + packs) as u64
+    }
+}
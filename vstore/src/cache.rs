@@ -0,0 +1,65 @@
+
+use std::sync::Arc;
+use parking_lot::Mutex;
+use log::debug;
+
+use keyvalue::KeyValue;
+use keyvalue::cache::{LruCache, CacheStats};
+use valuepack::Pack;
+
+use crate::Error;
+
+pub const DEFAULT_PACK_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+type PackLru = LruCache<(u64, u32), Arc<Pack>>;
+
+/// Decoded packs keyed by (version, partition), shared by every tree of a
+/// store. Entries are weighed by their encoded size.
+#[derive(Clone)]
+pub (crate) struct PackCache {
+    inner: Arc<Mutex<PackLru>>,
+}
+
+impl PackCache {
+    pub fn new(capacity: usize) -> Self {
+        PackCache {
+            inner: Arc::new(Mutex::new(LruCache::new(capacity))),
+        }
+    }
+
+    pub fn load(&self, kv: &Arc<Box<dyn KeyValue>>, ver: u64, part: u32) -> Result<Option<Arc<Pack>>, Error> {
+        if let Some(pack) = self.inner.lock().get(&(ver, part)) {
+            return Ok(Some(pack));
+        }
+
+        debug!("pack cache miss ver: {} part: {}", ver, part);
+
+        let buf = kv.get(ver, &part.to_be_bytes()[..]).map_err(|e| Error::from_pack_read(e, ver, part))?;
+        let pack = match buf {
+            None => None,
+            Some(buf) => {
+                let pack = Arc::new(Pack::from_buf(&buf)?);
+                self.insert(ver, part, pack.clone(), buf.len());
+                Some(pack)
+            }
+        };
+
+        Ok(pack)
+    }
+
+    pub fn insert(&self, ver: u64, part: u32, pack: Arc<Pack>, size: usize) {
+        self.inner.lock().insert((ver, part), pack, size);
+    }
+
+    pub fn clear(&self) {
+        self.inner.lock().clear();
+    }
+
+    pub fn set_capacity(&self, capacity: usize) {
+        self.inner.lock().set_capacity(capacity);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.inner.lock().stats()
+    }
+}
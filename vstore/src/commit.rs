@@ -1,16 +1,65 @@
 use std::sync::Arc;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use serde::{Deserialize, Serialize};
 use parking_lot::RwLock;
+use crate::branch::{self, Branch, BranchTable, MAIN_BRANCH};
+use crate::tag::{self, TagTable};
+use crate::gc::RetentionPolicy;
 use crate::Error;
 
 use log::debug;
 
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+// msgpack integers of any width decode into u64, so commit state and indexes
+// written with 16 bit versions load unchanged.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Commit {
-    pub ver: u16,
-    pub prev_ver: u16,
+    pub ver: u64,
+    pub prev_ver: u64,
+
+    #[serde(default)]
+    pub meta: CommitMeta,
+
+    /// Random, tells apart versions with the same number created by
+    /// different stores. Zero for commits made before it existed.
+    #[serde(default)]
+    pub id: u64,
+}
+
+/// Describes a commit, recorded by `VStore::commit_with`.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+pub struct CommitMeta {
+    pub message: String,
+    pub author: String,
+    /// Seconds since the unix epoch
+    pub timestamp: u64,
+    pub props: BTreeMap<String, String>,
+}
+
+impl CommitMeta {
+    pub fn new(message: &str) -> Self {
+        CommitMeta {
+            message: message.to_owned(),
+            ..CommitMeta::default()
+        }
+    }
+
+    pub fn author(mut self, author: &str) -> Self {
+        self.author = author.to_owned();
+        self
+    }
+
+    pub fn prop(mut self, key: &str, val: &str) -> Self {
+        self.props.insert(key.to_owned(), val.to_owned());
+        self
+    }
+}
+
+pub (crate) fn random_id() -> u64 {
+    let mut buf = [0u8; 8];
+    // Left zero on failure, which only weakens the check across stores
+    let _ = getrandom::getrandom(&mut buf);
+    u64::from_be_bytes(buf)
 }
 
 impl Commit {
@@ -52,18 +101,26 @@ impl CommitState {
         Ok(c)
     }
 
-    pub fn get_commit(&self, ver: u16) -> Option<Commit> {
+    /// Replaces the state with one read back from the store. Branches
+    /// and tags are loaded separately.
+    pub (crate) fn reload(&self, buf: &[u8]) -> Result<(), Error> {
+        let inner: CommitStateInner = rmp_serde::from_read_ref(buf)?;
+        *self.inner.write() = inner;
+        Ok(())
+    }
+
+    pub fn get_commit(&self, ver: u64) -> Option<Commit> {
         let i = self.inner.read();
         i.get_commit(ver)
 
     }
 
-    pub fn head_version(&self) -> u16 {
+    pub fn head_version(&self) -> u64 {
         let i = self.inner.read();
         i.head_ver
     }
 
-    pub fn open_version(&self) -> u16 {
+    pub fn open_version(&self) -> u64 {
         let i = self.inner.read();
         i.open_ver
     }
@@ -73,63 +130,613 @@ impl CommitState {
         i.open()
     }
 
+    /// True when `ver` is the open version of main or a branch.
+    pub fn is_open(&self, ver: u64) -> bool {
+        let i = self.inner.read();
+        i.is_open(ver)
+    }
+
     pub fn commit(&self) {
         let mut i = self.inner.write();
         i.commit();
     }
 
+    pub fn open_branch(&self, name: &str) -> Result<Commit, Error> {
+        let mut i = self.inner.write();
+        i.open_branch(name)
+    }
+
+    pub fn commit_branch(&self, name: &str) -> Result<(), Error> {
+        let mut i = self.inner.write();
+        i.commit_branch(name)
+    }
+
+    /// Closes `ver`, the open version of a line, without committing it.
+    /// The line's head goes back to the version it was opened from.
+    pub (crate) fn abort_branch(&self, name: &str, ver: u64) -> Result<(), Error> {
+        let mut i = self.inner.write();
+        i.abort_branch(name, ver)
+    }
+
+    /// Latest version reachable from both `a` and `b` through `prev_ver`.
+    pub fn common_ancestor(&self, a: u64, b: u64) -> Option<u64> {
+        let i = self.inner.read();
+
+        let mut seen = HashSet::new();
+        let mut ver = a;
+        while let Some(c) = i.cmap.get(&ver) {
+            seen.insert(c.ver);
+            ver = c.prev_ver;
+        }
+
+        let mut ver = b;
+        while let Some(c) = i.cmap.get(&ver) {
+            if seen.contains(&c.ver) {
+                return Some(c.ver);
+            }
+            ver = c.prev_ver;
+        }
+
+        None
+    }
+
+    /// Last committed version of main or a branch, 0 when there is none.
+    pub fn committed_head(&self, name: &str) -> Result<u64, Error> {
+        let i = self.inner.read();
+
+        let (head_ver, open_ver) = if name == MAIN_BRANCH {
+            (i.head_ver, i.open_ver)
+        }else{
+            let b = i.branches.get(name).ok_or(Error::BranchNotFound(name.to_owned()))?;
+            (b.head_ver, b.open_ver)
+        };
+
+        if open_ver == 0 {
+            return Ok(head_ver);
+        }
+
+        Ok(i.cmap.get(&open_ver).map(|c| c.prev_ver).unwrap_or(0))
+    }
+
+    /// Known versions, committed or open, in ascending order.
+    pub fn versions(&self) -> Vec<u64> {
+        let i = self.inner.read();
+        let mut vers: Vec<u64> = i.cmap.keys().cloned().collect();
+        vers.sort();
+        vers
+    }
+
+    /// Versions `policy` lets go, in ascending order.
+    pub (crate) fn expired(&self, policy: &RetentionPolicy, now: u64) -> Vec<u64> {
+        let i = self.inner.read();
+        i.expired(policy, now)
+    }
+
+    /// Forgets the given versions, commits on top of them are linked to
+    /// their closest kept ancestor and tags pointing at them are removed.
+    /// `retired` replaces the dropped versions that still hold packs.
+    pub (crate) fn drop_commits(&self, vers: &HashSet<u64>, retired: BTreeSet<u64>) {
+        let mut i = self.inner.write();
+        i.drop_commits(vers);
+        i.retired = retired;
+    }
+
+    /// Open versions of main and the branches.
+    pub (crate) fn open_versions(&self) -> Vec<u64> {
+        let i = self.inner.read();
+        i.lines().into_iter()
+            .map(|(_, open_ver)| open_ver)
+            .filter(|ver| *ver != 0)
+            .collect()
+    }
+
+    /// Versions strictly between `from` and its descendant `to`, which
+    /// must not be branch heads or open.
+    pub (crate) fn squash_range(&self, from: u64, to: u64) -> Result<Vec<u64>, Error> {
+        let i = self.inner.read();
+        i.squash_range(from, to)
+    }
+
+    /// Commits from `tip` back to `base` (exclusive, 0 for the root or the
+    /// start of a shallow history), oldest first.
+    pub fn chain(&self, base: u64, tip: u64) -> Result<Vec<Commit>, Error> {
+        let i = self.inner.read();
+        i.chain(base, tip)
+    }
+
+    /// Adds commits from another store, oldest first. Commits already
+    /// present unchanged are skipped, returns how many were new.
+    /// `boundary` is a parent allowed to be missing, see `set_shallow`.
+    pub (crate) fn import_commits(&self, commits: &[Commit], boundary: u64) -> Result<u64, Error> {
+        let mut i = self.inner.write();
+        i.import_commits(commits, boundary)
+    }
+
+    /// Marks the history as cut below `boundary`. `retired` lists versions
+    /// holding packs but no commit.
+    pub (crate) fn set_shallow(&self, boundary: u64, retired: &[u64]) {
+        let mut i = self.inner.write();
+        i.shallow_ver = boundary;
+        i.retired.extend(retired.iter().cloned());
+    }
+
+    /// Parent of the oldest commit of a shallow clone, 0 for a complete
+    /// history.
+    pub fn shallow_version(&self) -> u64 {
+        let i = self.inner.read();
+        i.shallow_ver
+    }
+
+    /// Moves the head of main or a branch forward to `ver`, which must
+    /// descend from the current head.
+    pub fn fast_forward(&self, name: &str, ver: u64) -> Result<(), Error> {
+        let mut i = self.inner.write();
+        i.fast_forward(name, ver)
+    }
+
+    pub (crate) fn retired(&self) -> Vec<u64> {
+        let i = self.inner.read();
+        i.retired.iter().cloned().collect()
+    }
+
+    pub fn set_meta(&self, ver: u64, meta: CommitMeta) -> Result<(), Error> {
+        let mut i = self.inner.write();
+        let c = i.cmap.get_mut(&ver).ok_or(Error::CommitNotFound(ver))?;
+        c.meta = meta;
+        Ok(())
+    }
+
+    pub fn create_branch(&self, name: &str, ver: u64) -> Result<(), Error> {
+        let mut i = self.inner.write();
+        i.create_branch(name, ver)
+    }
+
+    pub fn delete_branch(&self, name: &str) -> Result<(), Error> {
+        let mut i = self.inner.write();
+        i.delete_branch(name)
+    }
+
+    /// Every branch including main, by name.
+    pub fn branches(&self) -> Vec<(String, Branch)> {
+        let i = self.inner.read();
+
+        let main = Branch {
+            head_ver: i.head_ver,
+            open_ver: i.open_ver,
+        };
+
+        let mut list = vec![(MAIN_BRANCH.to_owned(), main)];
+        list.extend(i.branches.iter().map(|(n, b)| (n.clone(), b.clone())));
+        list
+    }
+
+    pub fn branches_to_vec(&self) -> Result<Vec<u8>, Error> {
+        let i = self.inner.read();
+        branch::table_to_vec(&i.branches)
+    }
+
+    pub fn load_branches(&self, buf: &[u8]) -> Result<(), Error> {
+        let mut i = self.inner.write();
+        i.branches = branch::table_from_buf(buf)?;
+        Ok(())
+    }
+
+    pub fn create_tag(&self, name: &str, ver: u64) -> Result<(), Error> {
+        let mut i = self.inner.write();
+        i.create_tag(name, ver)
+    }
+
+    pub fn delete_tag(&self, name: &str) -> Result<(), Error> {
+        let mut i = self.inner.write();
+        i.tags.remove(name).ok_or(Error::TagNotFound(name.to_owned()))?;
+        Ok(())
+    }
+
+    pub fn resolve_tag(&self, name: &str) -> Option<u64> {
+        let i = self.inner.read();
+        i.tags.get(name).cloned()
+    }
+
+    pub fn tags(&self) -> Vec<(String, u64)> {
+        let i = self.inner.read();
+        i.tags.iter().map(|(n, v)| (n.clone(), *v)).collect()
+    }
+
+    pub fn tags_to_vec(&self) -> Result<Vec<u8>, Error> {
+        let i = self.inner.read();
+        tag::table_to_vec(&i.tags)
+    }
+
+    pub fn load_tags(&self, buf: &[u8]) -> Result<(), Error> {
+        let mut i = self.inner.write();
+        i.tags = tag::table_from_buf(buf)?;
+        Ok(())
+    }
 }
 
 
 #[derive(Default, Debug, Clone, Deserialize, Serialize)]
 pub struct CommitStateInner {
-    head_ver: u16,
-    open_ver: u16,
-    cmap: HashMap<u16, Commit>,
+    head_ver: u64,
+    open_ver: u64,
+    cmap: HashMap<u64, Commit>,
+
+    /// Highest version handed out, versions are unique across branches.
+    #[serde(default)]
+    last_ver: u64,
+
+    /// Dropped versions still holding packs referenced by kept versions,
+    /// swept again by later collections.
+    #[serde(default)]
+    retired: BTreeSet<u64>,
+
+    /// Parent of the oldest commit of a shallow clone, absent from the
+    /// store. Zero when the history is complete.
+    #[serde(default)]
+    shallow_ver: u64,
+
+    /// Persisted under its own key, see `CommitState::branches_to_vec`
+    #[serde(skip)]
+    branches: BranchTable,
+
+    /// Persisted under its own key, see `CommitState::tags_to_vec`
+    #[serde(skip)]
+    tags: TagTable,
 }
 
 impl CommitStateInner {
-    fn get_commit(&self, ver: u16) -> Option<Commit> {
+    fn get_commit(&self, ver: u64) -> Option<Commit> {
         self.cmap.get(&ver).map(|c| c.clone())
     }
 
-    fn new_version_from_head(&mut self) -> Result<Commit, Error> {
-        let mut c = self.get_commit(self.head_ver)
-            .ok_or(Error::HeadVersionNotFound(self.head_ver))?;
+    fn next_version(&mut self) -> u64 {
+        let max_ver = self.cmap.keys().max().cloned().unwrap_or(0);
+        self.last_ver = self.last_ver.max(max_ver) + 1;
+        self.last_ver
+    }
 
-        c.increment();
-        Ok(c)
+    fn new_version_from(&mut self, head_ver: u64) -> Result<Commit, Error> {
+        let head = self.get_commit(head_ver)
+            .ok_or(Error::HeadVersionNotFound(head_ver))?;
+
+        Ok(Commit {
+            ver: self.next_version(),
+            prev_ver: head.ver,
+            meta: CommitMeta::default(),
+            id: random_id(),
+        })
     }
 
-    fn create_first_version(&self) -> Commit {
+    fn create_first_version(&mut self) -> Commit {
         Commit {
-            ver: 1,
+            ver: self.next_version(),
             prev_ver: 0,
+            meta: CommitMeta::default(),
+            id: random_id(),
         }
     } 
 
+    /// (head, open) version of main and every branch
+    fn lines(&self) -> Vec<(u64, u64)> {
+        let mut lines = vec![(self.head_ver, self.open_ver)];
+        lines.extend(self.branches.values().map(|b| (b.head_ver, b.open_ver)));
+        lines
+    }
+
+    fn expired(&self, policy: &RetentionPolicy, now: u64) -> Vec<u64> {
+        if !policy.limits() {
+            return Vec::new();
+        }
+
+        let mut keep = HashSet::new();
+
+        for (head_ver, open_ver) in self.lines() {
+            let mut ver = head_ver;
+            if open_ver != 0 {
+                keep.insert(open_ver);
+                ver = self.cmap.get(&open_ver).map(|c| c.prev_ver).unwrap_or(0);
+            }
+
+            let mut n = 0;
+            while let Some(c) = self.cmap.get(&ver) {
+                if n > 0 && policy.keep_last.map(|k| n >= k).unwrap_or(true) {
+                    break;
+                }
+
+                keep.insert(c.ver);
+                n += 1;
+                ver = c.prev_ver;
+            }
+        }
+
+        if policy.keep_tagged {
+            keep.extend(self.tags.values().cloned());
+        }
+
+        if let Some(max_age) = policy.max_age {
+            let recent = self.cmap.values()
+                .filter(|c| c.meta.timestamp != 0 && c.meta.timestamp.saturating_add(max_age) >= now);
+            keep.extend(recent.map(|c| c.ver));
+        }
+
+        let mut vers: Vec<u64> = self.cmap.keys()
+            .filter(|ver| !keep.contains(ver))
+            .cloned()
+            .collect();
+        vers.sort();
+        vers
+    }
+
+    fn squash_range(&self, from: u64, to: u64) -> Result<Vec<u64>, Error> {
+        let c = self.get_commit(to).ok_or(Error::CommitNotFound(to))?;
+        if self.is_open(to) {
+            return Err(Error::VersionNotCommitted(to));
+        }
+
+        let heads: HashSet<u64> = self.lines().into_iter()
+            .flat_map(|(head_ver, open_ver)| vec![head_ver, open_ver])
+            .collect();
+
+        let mut vers = Vec::new();
+        let mut ver = c.prev_ver;
+        while ver != from {
+            let c = self.cmap.get(&ver).ok_or(Error::NotAncestor(from, to))?;
+            if heads.contains(&ver) {
+                return Err(Error::VersionInUse(ver));
+            }
+
+            vers.push(ver);
+            ver = c.prev_ver;
+        }
+
+        vers.sort();
+        Ok(vers)
+    }
+
+    fn chain(&self, base: u64, tip: u64) -> Result<Vec<Commit>, Error> {
+        if self.is_open(tip) {
+            return Err(Error::VersionNotCommitted(tip));
+        }
+
+        let mut commits = Vec::new();
+        let mut ver = tip;
+        while ver != base {
+            if ver == 0 {
+                return Err(Error::NotAncestor(base, tip));
+            }
+
+            // Walks to the root end where a shallow clone's history does
+            if ver == self.shallow_ver && base == 0 {
+                break;
+            }
+
+            let c = self.cmap.get(&ver).ok_or(Error::CommitNotFound(ver))?;
+            commits.push(c.clone());
+            ver = c.prev_ver;
+        }
+
+        commits.reverse();
+        Ok(commits)
+    }
+
+    fn import_commits(&mut self, commits: &[Commit], boundary: u64) -> Result<u64, Error> {
+        let mut new = Vec::new();
+
+        for c in commits {
+            match self.cmap.get(&c.ver) {
+                Some(e) if e == c && !self.is_open(c.ver) => continue,
+                Some(_) => return Err(Error::BundleConflict(c.ver)),
+                None => {},
+            }
+
+            let prev_known = c.prev_ver == 0
+                || c.prev_ver == boundary
+                || self.cmap.contains_key(&c.prev_ver)
+                || new.iter().any(|n: &Commit| n.ver == c.prev_ver);
+            if !prev_known {
+                return Err(Error::CommitNotFound(c.prev_ver));
+            }
+
+            new.push(c.clone());
+        }
+
+        let count = new.len() as u64;
+        for c in new {
+            self.last_ver = self.last_ver.max(c.ver);
+            self.cmap.insert(c.ver, c);
+        }
+
+        Ok(count)
+    }
+
+    fn is_ancestor(&self, a: u64, b: u64) -> bool {
+        let mut ver = b;
+        while let Some(c) = self.cmap.get(&ver) {
+            if c.ver == a {
+                return true;
+            }
+            ver = c.prev_ver;
+        }
+
+        false
+    }
+
+    fn fast_forward(&mut self, name: &str, ver: u64) -> Result<(), Error> {
+        let (head_ver, open_ver) = if name == MAIN_BRANCH {
+            (self.head_ver, self.open_ver)
+        }else{
+            let b = self.branches.get(name).ok_or(Error::BranchNotFound(name.to_owned()))?;
+            (b.head_ver, b.open_ver)
+        };
+
+        if open_ver != 0 {
+            return Err(Error::VersionInUse(open_ver));
+        }
+
+        if !self.cmap.contains_key(&ver) || self.is_open(ver) {
+            return Err(Error::VersionNotCommitted(ver));
+        }
+
+        if head_ver != 0 && !self.is_ancestor(head_ver, ver) {
+            return Err(Error::NotAncestor(head_ver, ver));
+        }
+
+        if name == MAIN_BRANCH {
+            self.head_ver = ver;
+        }else if let Some(b) = self.branches.get_mut(name) {
+            b.head_ver = ver;
+        }
+
+        Ok(())
+    }
+
+    fn drop_commits(&mut self, vers: &HashSet<u64>) {
+        let prev: HashMap<u64, u64> = vers.iter()
+            .filter_map(|ver| self.cmap.remove(ver))
+            .map(|c| (c.ver, c.prev_ver))
+            .collect();
+
+        for c in self.cmap.values_mut() {
+            while let Some(p) = prev.get(&c.prev_ver) {
+                c.prev_ver = *p;
+            }
+        }
+
+        self.tags.retain(|_, ver| !vers.contains(ver));
+    }
+
+    fn is_open(&self, ver: u64) -> bool {
+        ver != 0 && (self.open_ver == ver || self.branches.values().any(|b| b.open_ver == ver))
+    }
+
     fn commit(&mut self) {
         self.open_ver = 0;
     }
 
-    fn open(&mut self) -> Result<Commit, Error> {
-        let c = if self.head_ver == 0 {
+    /// Open version of the line with the given head, creating it if needed
+    fn open_line(&mut self, head_ver: u64, open_ver: u64) -> Result<Commit, Error> {
+        let c = if head_ver == 0 {
             debug!("creating first version");
             self.create_first_version()
         }else{
-            if self.open_ver == 0 {
+            if open_ver == 0 {
                 debug!("creating version from head");
-                self.new_version_from_head()?
+                self.new_version_from(head_ver)?
             }else {
-                self.get_commit(self.open_ver)
-                    .ok_or(Error::VersionNotFound(self.open_ver))?
+                self.get_commit(open_ver)
+                    .ok_or(Error::VersionNotFound(open_ver))?
             }
         };
 
+        self.cmap.insert(c.ver, c.clone());
+        Ok(c)
+    }
+
+    fn open(&mut self) -> Result<Commit, Error> {
+        let c = self.open_line(self.head_ver, self.open_ver)?;
+
         self.head_ver = c.ver;
         self.open_ver = c.ver;
-        self.cmap.insert(c.ver, c.clone());
 
         Ok(c)
     }
-}
\ No newline at end of file
+
+    fn open_branch(&mut self, name: &str) -> Result<Commit, Error> {
+        if name == MAIN_BRANCH {
+            return self.open();
+        }
+
+        let b = self.branches.get(name).cloned()
+            .ok_or(Error::BranchNotFound(name.to_owned()))?;
+
+        let c = self.open_line(b.head_ver, b.open_ver)?;
+        self.branches.insert(name.to_owned(), Branch {
+            head_ver: c.ver,
+            open_ver: c.ver,
+        });
+
+        Ok(c)
+    }
+
+    fn commit_branch(&mut self, name: &str) -> Result<(), Error> {
+        if name == MAIN_BRANCH {
+            self.commit();
+            return Ok(());
+        }
+
+        let b = self.branches.get_mut(name)
+            .ok_or(Error::BranchNotFound(name.to_owned()))?;
+
+        b.open_ver = 0;
+        Ok(())
+    }
+
+    fn abort_branch(&mut self, name: &str, ver: u64) -> Result<(), Error> {
+        let open_ver = if name == MAIN_BRANCH {
+            self.open_ver
+        }else{
+            self.branches.get(name).ok_or(Error::BranchNotFound(name.to_owned()))?.open_ver
+        };
+
+        if open_ver == 0 || open_ver != ver {
+            return Err(Error::NotOpen(ver));
+        }
+
+        let prev_ver = self.get_commit(ver).ok_or(Error::VersionNotFound(ver))?.prev_ver;
+
+        if name == MAIN_BRANCH {
+            self.head_ver = prev_ver;
+            self.open_ver = 0;
+        }else if let Some(b) = self.branches.get_mut(name) {
+            b.head_ver = prev_ver;
+            b.open_ver = 0;
+        }
+
+        Ok(())
+    }
+
+    fn create_branch(&mut self, name: &str, ver: u64) -> Result<(), Error> {
+        if name == MAIN_BRANCH {
+            return Err(Error::BranchReserved(name.to_owned()));
+        }
+
+        if self.branches.contains_key(name) {
+            return Err(Error::BranchExists(name.to_owned()));
+        }
+
+        if !self.cmap.contains_key(&ver) || self.is_open(ver) {
+            return Err(Error::VersionNotCommitted(ver));
+        }
+
+        self.branches.insert(name.to_owned(), Branch {
+            head_ver: ver,
+            open_ver: 0,
+        });
+
+        Ok(())
+    }
+
+    fn create_tag(&mut self, name: &str, ver: u64) -> Result<(), Error> {
+        tag::check_name(name)?;
+
+        if self.tags.contains_key(name) {
+            return Err(Error::TagExists(name.to_owned()));
+        }
+
+        if !self.cmap.contains_key(&ver) || self.is_open(ver) {
+            return Err(Error::VersionNotCommitted(ver));
+        }
+
+        self.tags.insert(name.to_owned(), ver);
+        Ok(())
+    }
+
+    fn delete_branch(&mut self, name: &str) -> Result<(), Error> {
+        if name == MAIN_BRANCH {
+            return Err(Error::BranchReserved(name.to_owned()));
+        }
+
+        self.branches.remove(name).ok_or(Error::BranchNotFound(name.to_owned()))?;
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 
 use std::sync::Arc;
 use crate::index::Index;
+use crate::cache::PackCache;
 use keyvalue::KeyValue;
 use valuepack::Pack;
 use log::debug;
@@ -11,17 +12,19 @@ pub struct DiffIter {
     pub (crate) a_idx: Index,
     pub (crate) b_idx: Index,
 
-    a_pack: Option<Pack>,
-    b_pack: Option<Pack>,
+    packs: PackCache,
+    a_pack: Option<Arc<Pack>>,
+    b_pack: Option<Arc<Pack>>,
     pos: usize,
 }
 
 impl DiffIter {
-    pub (crate) fn new(kv: Arc<Box<dyn KeyValue>>, a_idx: Index, b_idx: Index) -> Self {
+    pub (crate) fn new(kv: Arc<Box<dyn KeyValue>>, packs: PackCache, a_idx: Index, b_idx: Index) -> Self {
         let d = DiffIter {
             a_idx,
             b_idx,
             kv,
+            packs,
             pos: 0,
             a_pack: None,
             b_pack: None,
@@ -29,10 +32,20 @@ impl DiffIter {
         d
     }
 
-    fn next_part_diff(&mut self) -> Option<(usize, u16, u16)> {
-        while self.pos < self.a_idx.len() {
-            let a_part_ver = self.a_idx.get_prefix_version(self.pos);
-            let b_part_ver = self.b_idx.get_prefix_version(self.pos);
+    /// Indexes of a resharded store can differ in partition count. Parts
+    /// are walked at the finer count, a coarse part covering several.
+    fn shifts(&self) -> (usize, usize) {
+        let (a, b) = (self.a_idx.prefix_bits(), self.b_idx.prefix_bits());
+        (b.saturating_sub(a), a.saturating_sub(b))
+    }
+
+    fn next_part_diff(&mut self) -> Option<(usize, u64, u64)> {
+        let (a_shift, b_shift) = self.shifts();
+        let len = self.a_idx.len().max(self.b_idx.len());
+
+        while self.pos < len {
+            let a_part_ver = self.a_idx.get_prefix_version(self.pos >> a_shift);
+            let b_part_ver = self.b_idx.get_prefix_version(self.pos >> b_shift);
             if a_part_ver == 0 && (a_part_ver == b_part_ver) {
                 self.pos += 1;
             }else{
@@ -44,20 +57,38 @@ impl DiffIter {
         None
     }
 
-    fn load_pack(&self, part: usize, part_ver: u16) -> Result<Option<Pack>, Error> {
-        if part == 0 {
+    /// Pack holding `part` at `part_ver`, cut down to the keys of `part`
+    /// when it is a coarse one.
+    fn load_pack(&self, part: usize, part_ver: u64, shift: usize) -> Result<Option<Arc<Pack>>, Error> {
+        if part_ver == 0 {
             return Ok(None)
         }
 
-        let part = part as u32;
-        let part_key = part.to_be_bytes();
+        let pack = match self.packs.load(&self.kv, part_ver, (part >> shift) as u32)? {
+            Some(pack) => pack,
+            None => return Ok(None),
+        };
+
+        if shift == 0 {
+            return Ok(Some(pack));
+        }
+
+        let fine = match self.a_idx.prefix_bits() > self.b_idx.prefix_bits() {
+            true => &self.a_idx,
+            false => &self.b_idx,
+        };
 
-        match self.kv.get(part_ver, &part_key[..])? {
-            None => Ok(None),
-            Some(buf) => {
-                Ok(Some(Pack::from_buf(&buf)?))
+        let mut cut = Pack::new();
+        for (key, (ver, val)) in pack.map.iter() {
+            if fine.get_part(key).1 as usize == part {
+                cut.put(*ver, key, val.clone());
             }
         }
+
+        match cut.map.is_empty() {
+            true => Ok(None),
+            false => Ok(Some(Arc::new(cut))),
+        }
     }
 
     fn all_new(&self, p: &Pack) -> Vec<DiffItem> {
@@ -156,9 +187,15 @@ impl DiffIter {
             }
 
             let (part, a_part_ver, b_part_ver) = part_diff_ver.unwrap();
+            let (a_shift, b_shift) = self.shifts();
+
+            self.a_pack = self.load_pack(part, a_part_ver, a_shift)?;
+            self.b_pack = self.load_pack(part, b_part_ver, b_shift)?;
 
-            self.a_pack = self.load_pack(part, a_part_ver)?;
-            self.b_pack = self.load_pack(part, b_part_ver)?;
+            // A coarse pack can hold no keys of this part
+            if (a_shift > 0 || b_shift > 0) && self.a_pack.is_none() && self.b_pack.is_none() {
+                return Ok(Some(Vec::new()));
+            }
         }
 
         let items = self.process_packs()?;
@@ -169,15 +206,15 @@ impl DiffIter {
 }
 
 pub struct DiffValue {
-    pub a_ver: u16,
-    pub b_ver: u16,
+    pub a_ver: u64,
+    pub b_ver: u64,
     pub a_val: Vec<u8>,
     pub b_val: Vec<u8>,
 }
 
 pub enum DiffType {
     New(Vec<u8>),
-    Delete(u16, Vec<u8>),
+    Delete(u64, Vec<u8>),
     Value(DiffValue),
 }
 
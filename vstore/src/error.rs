@@ -10,23 +10,86 @@ pub enum Error {
     #[error("Vstore already initialized")]
     AlreadyInitedError,
 
+    #[error("Invalid store options {0}")]
+    InvalidOptions(String),
+
     #[error("Index not found")]
     IndexNotFound,
 
+    #[error("Index corrupted v={0}")]
+    IndexCorrupted(u64),
+
     #[error("Diff error")]
     InvalidDiffState,
 
     #[error("Pack not found v={0} p={1}")]
-    PackNotFound(u16, u32),
+    PackNotFound(u64, u32),
+
+    #[error("Pack corrupted v={0} p={1}")]
+    PackCorrupted(u64, u32),
 
     #[error("Commit not found v={0}")]
-    CommitNotFound(u16),
+    CommitNotFound(u64),
 
     #[error("Head version not found v={0}")]
-    HeadVersionNotFound(u16),
+    HeadVersionNotFound(u64),
 
     #[error("Version not found v={0}")]
-    VersionNotFound(u16),
+    VersionNotFound(u64),
+
+    #[error("Version not committed v={0}")]
+    VersionNotCommitted(u64),
+
+    #[error("Version is not open v={0}")]
+    NotOpen(u64),
+
+    #[error("Branch not found {0}")]
+    BranchNotFound(String),
+
+    #[error("Branch already exists {0}")]
+    BranchExists(String),
+
+    #[error("Branch is reserved {0}")]
+    BranchReserved(String),
+
+    #[error("Tag not found {0}")]
+    TagNotFound(String),
+
+    #[error("Tag already exists {0}")]
+    TagExists(String),
+
+    #[error("Invalid tag name {0:?}")]
+    InvalidTagName(String),
+
+    #[error("Version v={0} is not an ancestor of v={1}")]
+    NotAncestor(u64, u64),
+
+    #[error("Version is a branch head or open v={0}")]
+    VersionInUse(u64),
+
+    #[error("Invalid bundle {0}")]
+    BundleInvalid(String),
+
+    #[error("Version exists with a different history v={0}")]
+    BundleConflict(u64),
+
+    #[error("Histories of {0} have diverged")]
+    Diverged(String),
+
+    #[error("Remote error {0}")]
+    Remote(String),
+
+    #[error("Merge conflict on key {0:?}")]
+    MergeConflict(Vec<u8>),
+
+    #[error("Versions use different partitionings")]
+    PartitionMismatch,
+
+    #[error("Store is locked by writer pid={0} since {1}")]
+    Locked(u32, u64),
+
+    #[error("Commit state changed by another writer, reopen the store")]
+    ConcurrentWrite,
 
     #[error("I/O error")]
     IOError(#[from] std::io::Error),
@@ -42,4 +105,14 @@ pub enum Error {
 
     #[error("Unknown KeyValue error")]
     Unknown,
-}
\ No newline at end of file
+}
+
+impl Error {
+    /// Names the damaged pack when the KeyValue layer detected corruption.
+    pub (crate) fn from_pack_read(e: keyvalue::Error, ver: u64, part: u32) -> Error {
+        match e {
+            keyvalue::Error::Corruption{..} => Error::PackCorrupted(ver, part),
+            e => Error::KVError(e),
+        }
+    }
+}
@@ -0,0 +1,55 @@
+
+/// Which commits `VStore::gc` keeps. Branch heads and open versions are
+/// always kept. Without `keep_last` or `max_age` nothing expires, the
+/// default policy keeps everything.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Commits kept on each branch counting back from its head. Unset with
+    /// `max_age` set, only the heads are kept by count.
+    pub keep_last: Option<usize>,
+    pub keep_tagged: bool,
+    /// Seconds, commits without a timestamp are never kept by age
+    pub max_age: Option<u64>,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            keep_last: None,
+            keep_tagged: true,
+            max_age: None,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// True when the policy can expire a commit at all.
+    pub fn limits(&self) -> bool {
+        self.keep_last.is_some() || self.max_age.is_some()
+    }
+
+    pub fn keep_last(mut self, n: usize) -> Self {
+        self.keep_last = Some(n);
+        self
+    }
+
+    pub fn keep_tagged(mut self, keep: bool) -> Self {
+        self.keep_tagged = keep;
+        self
+    }
+
+    pub fn max_age(mut self, secs: u64) -> Self {
+        self.max_age = Some(secs);
+        self
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct GcStats {
+    /// Commits dropped
+    pub versions: u64,
+    /// Index and pack records deleted
+    pub records: u64,
+    /// Size of the deleted records as read through the store's KeyValue
+    pub bytes: u64,
+}
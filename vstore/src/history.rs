@@ -0,0 +1,34 @@
+use crate::commit::Commit;
+
+#[derive(Debug, Clone)]
+pub enum KeyChange {
+    Created(Vec<u8>),
+    Modified(Vec<u8>),
+    Deleted,
+}
+
+/// A commit that changed a key, with the key's value after it.
+#[derive(Debug, Clone)]
+pub struct KeyEvent {
+    pub commit: Commit,
+    pub change: KeyChange,
+}
+
+/// Entry of the key at one version, as (entry version, value).
+pub (crate) type KeyState = Option<(u64, Vec<u8>)>;
+
+/// Change between the key's state before and after a commit, if any.
+pub (crate) fn change(before: &KeyState, after: &KeyState) -> Option<KeyChange> {
+    match (before, after) {
+        (None, None) => None,
+        (None, Some((_, val))) => Some(KeyChange::Created(val.clone())),
+        (Some(_), None) => Some(KeyChange::Deleted),
+        (Some((bv, bval)), Some((av, aval))) => {
+            if bv == av && bval == aval {
+                None
+            }else{
+                Some(KeyChange::Modified(aval.clone()))
+            }
+        },
+    }
+}
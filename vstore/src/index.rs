@@ -1,17 +1,57 @@
-
 use std::sync::Arc;
+use std::collections::BTreeMap;
 
 use serde::{Deserialize, Serialize};
 use parking_lot::RwLock;
 use xxhash_rust::xxh3::xxh3_64;
 
+use keyvalue::KeyValue;
 use crate::Error;
 
-#[derive(Deserialize, Serialize)]
+/// Delta records layered on one another before a full record is written
+/// again.
+pub (crate) const MAX_DELTA_DEPTH: u32 = 16;
+
+// Stored form. A full record has the whole version list, a delta record
+// only the partitions changed since the record at `parent`. Records
+// written before deltas existed decode as full ones.
+#[derive(Deserialize)]
+struct IndexRecord {
+    #[allow(dead_code)]
+    ver: u64,
+    prefix_bits: usize,
+    version_list: Vec<u64>,
+    #[serde(default)]
+    parent: u64,
+    #[serde(default)]
+    changes: BTreeMap<u32, u64>,
+    /// Delta records between this one and a full record
+    #[serde(default)]
+    depth: u32,
+}
+
+#[derive(Serialize)]
+struct IndexRecordRef<'a> {
+    ver: u64,
+    prefix_bits: usize,
+    version_list: &'a [u64],
+    parent: u64,
+    changes: &'a BTreeMap<u32, u64>,
+    depth: u32,
+}
+
 struct IndexInner {
-    ver: u16,
+    ver: u64,
     prefix_bits: usize,
-    version_list: Vec<u16>,
+    version_list: Vec<u64>,
+
+    /// Record the next delta is written against, 0 for none
+    parent: u64,
+    parent_depth: u32,
+    /// Partitions set since `parent`
+    changes: BTreeMap<u32, u64>,
+    /// Versions of the records this index was resolved from, newest first
+    chain: Vec<u64>,
 }
 
 #[derive(Clone)]
@@ -22,13 +62,17 @@ pub (crate) struct Index {
 }
 
 impl Index {
-    pub fn new(prefix_bits: usize, ver: u16) -> Self {
+    pub fn new(prefix_bits: usize, ver: u64) -> Self {
         let len: usize = 2usize.pow(prefix_bits as u32);
 
         let inner = IndexInner {
             ver,
             prefix_bits,
             version_list: vec![0; len],
+            parent: 0,
+            parent_depth: 0,
+            changes: BTreeMap::new(),
+            chain: Vec::new(),
         };
 
         let idx = Index{
@@ -40,39 +84,219 @@ impl Index {
         idx
     }
 
-    pub fn get_prefix_version(&self, part: usize) -> u16 {
+    /// Same partitions as `other`, as the index of `ver` written whole.
+    pub fn copy_of(other: &Index, ver: u64) -> Self {
+        let o = other.inner.read();
+
+        let inner = IndexInner {
+            ver,
+            prefix_bits: o.prefix_bits,
+            version_list: o.version_list.clone(),
+            parent: 0,
+            parent_depth: 0,
+            changes: BTreeMap::new(),
+            chain: Vec::new(),
+        };
+
+        Index {
+            prefix_bits: o.prefix_bits,
+            inner: Arc::new(RwLock::new(inner)),
+        }
+    }
+
+    pub fn get_prefix_version(&self, part: usize) -> u64 {
         let inner = self.inner.read();
         inner.version_list[part]
     }
 
+    pub fn prefix_bits(&self) -> usize {
+        self.prefix_bits
+    }
+
     pub fn len(&self) -> usize {
         let inner = self.inner.read();
         inner.version_list.len()
     }
 
-    pub fn set_version(&self, ver: u16) {
+    pub fn set_version(&self, ver: u64) {
         let mut inner = self.inner.write();
         inner.ver = ver
     }
 
-    pub fn new_with_buf(buf: &[u8]) -> Result<Self, Error> {
-        let inner: IndexInner = rmp_serde::from_read_ref(buf)?;
+    /// Index stored at `ver`, resolving delta records through their
+    /// parents.
+    pub fn load(kv: &Arc<Box<dyn KeyValue>>, ver: u64) -> Result<Option<Self>, Error> {
+        Index::load_with(ver, |ver| Ok(kv.get(ver, "index".as_bytes())?))
+    }
 
-        let idx = Index{
-            prefix_bits: inner.prefix_bits,
+    /// Like `load`, reading records through `get`. The result writes its
+    /// next delta against the same parent as the record at `ver` did.
+    pub fn load_with<F>(ver: u64, mut get: F) -> Result<Option<Self>, Error>
+        where F: FnMut(u64) -> Result<Option<Vec<u8>>, Error> {
+
+        let top: IndexRecord = match get(ver)? {
+            Some(buf) => rmp_serde::from_read_ref(&buf)?,
+            None => return Ok(None),
+        };
+
+        let parent = top.parent;
+        let parent_depth = top.depth.saturating_sub(1);
+        let changes = top.changes.clone();
+        let mut chain = vec![ver];
+
+        let mut deltas = Vec::new();
+        let mut rec = top;
+        while rec.parent != 0 {
+            if deltas.len() > MAX_DELTA_DEPTH as usize {
+                return Err(Error::IndexCorrupted(ver));
+            }
+
+            let pver = rec.parent;
+            let buf = get(pver)?.ok_or(Error::IndexCorrupted(ver))?;
+            deltas.push(rec);
+            rec = rmp_serde::from_read_ref(&buf)?;
+            chain.push(pver);
+        }
+
+        let mut version_list = rec.version_list;
+        for d in deltas.iter().rev() {
+            for (part, pver) in d.changes.iter() {
+                let slot = version_list.get_mut(*part as usize).ok_or(Error::IndexCorrupted(ver))?;
+                *slot = *pver;
+            }
+        }
+
+        let inner = IndexInner {
+            ver,
+            prefix_bits: rec.prefix_bits,
+            version_list,
+            parent,
+            parent_depth,
+            changes,
+            chain,
+        };
+
+        Ok(Some(Index {
+            prefix_bits: rec.prefix_bits,
             inner: Arc::new(RwLock::new(inner)),
-            //db,
+        }))
+    }
+
+    /// Writes the next delta against the record this index was loaded
+    /// from, stored at `ver`.
+    pub fn layer_on(&self, ver: u64) {
+        let mut inner = self.inner.write();
+        inner.parent_depth = match inner.parent {
+            0 => 0,
+            _ => inner.parent_depth + 1,
         };
+        inner.parent = ver;
+        inner.changes.clear();
+    }
 
-        Ok(idx)
+    /// Versions of the records read to resolve this index, its own first.
+    pub fn chain(&self) -> Vec<u64> {
+        let inner = self.inner.read();
+        inner.chain.clone()
     }
 
+    /// Depth of the record `to_record` writes next.
+    pub fn depth(&self) -> u32 {
+        let inner = self.inner.read();
+        match inner.parent {
+            0 => 0,
+            _ if inner.parent_depth + 1 > MAX_DELTA_DEPTH => 0,
+            _ => inner.parent_depth + 1,
+        }
+    }
+
+    /// Full record.
     pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
         let inner = self.inner.read();
-        let buf = rmp_serde::to_vec(&*inner)?;
+        let empty = BTreeMap::new();
+
+        let rec = IndexRecordRef {
+            ver: inner.ver,
+            prefix_bits: inner.prefix_bits,
+            version_list: &inner.version_list,
+            parent: 0,
+            changes: &empty,
+            depth: 0,
+        };
+
+        let buf = rmp_serde::to_vec(&rec)?;
+        Ok(buf)
+    }
+
+    /// Delta record against the parent, or a full record when there is
+    /// none or the chain of deltas is long enough. A parent dropped from
+    /// `kv` meanwhile, by gc or squash, is let go of for good.
+    pub fn to_record(&self, kv: &Arc<Box<dyn KeyValue>>) -> Result<Vec<u8>, Error> {
+        let depth = self.depth();
+        if depth == 0 {
+            return self.to_vec();
+        }
+
+        let parent = self.inner.read().parent;
+        if kv.get(parent, "index".as_bytes())?.is_none() {
+            let mut inner = self.inner.write();
+            inner.parent = 0;
+            inner.parent_depth = 0;
+            drop(inner);
+            return self.to_vec();
+        }
+
+        let inner = self.inner.read();
+        let rec = IndexRecordRef {
+            ver: inner.ver,
+            prefix_bits: inner.prefix_bits,
+            version_list: &[],
+            parent: inner.parent,
+            changes: &inner.changes,
+            depth,
+        };
+
+        let buf = rmp_serde::to_vec(&rec)?;
         Ok(buf)
     }
 
+    /// Delta record against `parent`, stored at `parent_ver` with depth
+    /// `parent_depth`. Returns it with its own depth.
+    pub fn delta_vec(&self, parent: &Index, parent_ver: u64, parent_depth: u32) -> Result<(Vec<u8>, u32), Error> {
+        if parent_depth + 1 > MAX_DELTA_DEPTH || parent.len() != self.len() {
+            return Ok((self.to_vec()?, 0));
+        }
+
+        let inner = self.inner.read();
+        let pinner = parent.inner.read();
+
+        let changes: BTreeMap<u32, u64> = inner.version_list.iter()
+            .zip(pinner.version_list.iter())
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(part, (a, _))| (part as u32, *a))
+            .collect();
+
+        let rec = IndexRecordRef {
+            ver: inner.ver,
+            prefix_bits: inner.prefix_bits,
+            version_list: &[],
+            parent: parent_ver,
+            changes: &changes,
+            depth: parent_depth + 1,
+        };
+
+        let buf = rmp_serde::to_vec(&rec)?;
+        Ok((buf, parent_depth + 1))
+    }
+
+    /// Same partitioning with every partition at the same version.
+    pub fn same_parts(&self, other: &Index) -> bool {
+        let inner = self.inner.read();
+        let oinner = other.inner.read();
+        inner.version_list == oinner.version_list
+    }
+
     fn hash(&self, key: &[u8]) -> (u32, [u8; 8]) {
         let h = xxh3_64(key);
 
@@ -80,7 +304,7 @@ impl Index {
         (part as u32, h.to_be_bytes())
     }
 
-    pub fn get_part(&self, key: &[u8]) -> (u16, u32, [u8; 8]) {
+    pub fn get_part(&self, key: &[u8]) -> (u64, u32, [u8; 8]) {
         let (part, h) = self.hash(key);
         let inner = self.inner.read();
         let ver = inner.version_list[part as usize];
@@ -88,8 +312,80 @@ impl Index {
         (ver, part, h)
     }
 
-    pub fn set_part(&self, part: u32, ver: u16) {
+    pub fn set_part(&self, part: u32, ver: u64) {
         let mut inner = self.inner.write();
         inner.version_list[part as usize] = ver;
+        inner.changes.insert(part, ver);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keyvalue::memory::MemoryKV;
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn delta_chain_past_max_depth() {
+        let kv: Arc<Box<dyn KeyValue>> = Arc::new(MemoryKV::new_box());
+        let idx = Index::new(4, 1);
+        idx.set_part(0, 1);
+        kv.put(1, b"index", &idx.to_record(&kv).unwrap()).unwrap();
+
+        let max = MAX_DELTA_DEPTH as u64;
+        let last = 2 * max + 3;
+        for ver in 2..=last {
+            // As a tree opening the next version does
+            let idx = Index::load(&kv, ver - 1).unwrap().unwrap();
+            idx.layer_on(ver - 1);
+            idx.set_version(ver);
+            idx.set_part((ver % 16) as u32, ver);
+            kv.put(ver, b"index", &idx.to_record(&kv).unwrap()).unwrap();
+        }
+
+        let mut full = Vec::new();
+        for ver in 1..=last {
+            let idx = Index::load(&kv, ver).unwrap().unwrap();
+            if idx.chain().len() == 1 {
+                full.push(ver);
+            }
+
+            for part in 0..16 {
+                let expected = (2..=ver).rev()
+                    .find(|v| v % 16 == part)
+                    .unwrap_or(if part == 0 { 1 } else { 0 });
+                assert_eq!(idx.get_prefix_version(part as usize), expected);
+            }
+        }
+        assert_eq!(full, vec![1, max + 2, last]);
+
+        // A delta whose parent is gone cannot be resolved
+        kv.delete(2, b"index").unwrap();
+        assert!(Index::load(&kv, 3).is_err());
+    }
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn delta_parent_dropped() {
+        let kv: Arc<Box<dyn KeyValue>> = Arc::new(MemoryKV::new_box());
+        let idx = Index::new(4, 1);
+        idx.set_part(0, 1);
+        kv.put(1, b"index", &idx.to_record(&kv).unwrap()).unwrap();
+
+        let idx = Index::load(&kv, 1).unwrap().unwrap();
+        idx.layer_on(1);
+        idx.set_version(2);
+        idx.set_part(1, 2);
+        kv.put(2, b"index", &idx.to_record(&kv).unwrap()).unwrap();
+        assert_eq!(Index::load(&kv, 2).unwrap().unwrap().chain(), vec![2, 1]);
+
+        // Dropped while the index of 2 is still in use
+        kv.delete(1, b"index").unwrap();
+        idx.set_part(2, 2);
+        kv.put(2, b"index", &idx.to_record(&kv).unwrap()).unwrap();
+
+        let idx = Index::load(&kv, 2).unwrap().unwrap();
+        assert_eq!(idx.chain(), vec![2]);
+        assert_eq!((0..4).map(|part| idx.get_prefix_version(part)).collect::<Vec<_>>(), vec![1, 2, 2, 0]);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,116 @@
+use std::sync::Arc;
+use std::collections::VecDeque;
+use crate::index::Index;
+use crate::cache::PackCache;
+use keyvalue::KeyValue;
+use log::debug;
+use crate::Error;
+
+/// Live keys and values of a tree, one partition pack at a time. Keys come
+/// in partition order, sorted within each pack; use `sorted` for key order.
+pub struct TreeIter {
+    kv: Arc<Box<dyn KeyValue>>,
+    packs: PackCache,
+    idx: Index,
+    prefix: Option<Vec<u8>>,
+    pos: usize,
+    items: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Items of a tree in key order, see `TreeIter::sorted`
+type SortedItems = std::vec::IntoIter<(Vec<u8>, Vec<u8>)>;
+
+#[derive(Debug, Default, Clone)]
+pub struct TreeSummary {
+    pub keys: u64,
+    /// Total length of keys and values
+    pub bytes: u64,
+    pub packs: u64,
+}
+
+impl TreeIter {
+    pub (crate) fn new(kv: Arc<Box<dyn KeyValue>>, packs: PackCache, idx: Index, prefix: Option<Vec<u8>>) -> Self {
+        TreeIter {
+            kv,
+            packs,
+            idx,
+            prefix,
+            pos: 0,
+            items: VecDeque::new(),
+        }
+    }
+
+    /// Loads the next non empty partition, false when there is none left.
+    fn fill(&mut self) -> Result<bool, Error> {
+        while self.pos < self.idx.len() {
+            let part = self.pos;
+            self.pos += 1;
+
+            let part_ver = self.idx.get_prefix_version(part);
+            if part_ver == 0 {
+                continue;
+            }
+
+            debug!("tree iter pack ver: {} part: {}", part_ver, part);
+            let pack = self.packs.load(&self.kv, part_ver, part as u32)?
+                .ok_or(Error::PackNotFound(part_ver, part as u32))?;
+
+            let prefix = self.prefix.as_deref().unwrap_or(&[]);
+            let mut items: Vec<(Vec<u8>, Vec<u8>)> = pack.map.iter()
+                .filter(|(k, _)| k.starts_with(prefix))
+                .map(|(k, v)| (k.clone(), v.1.clone()))
+                .collect();
+
+            if items.is_empty() {
+                continue;
+            }
+
+            items.sort();
+            self.items.extend(items);
+            return Ok(true);
+        }
+
+        Ok(false)
+    }
+
+    /// Collects the remaining items in key order. Holds them all in memory.
+    pub fn sorted(self) -> Result<SortedItems, Error> {
+        let mut items = self.collect::<Result<Vec<_>, Error>>()?;
+        items.sort();
+        Ok(items.into_iter())
+    }
+
+    /// Counts the remaining items without keeping them.
+    pub fn summary(mut self) -> Result<TreeSummary, Error> {
+        let mut s = TreeSummary::default();
+
+        while self.fill()? {
+            s.packs += 1;
+            for (k, v) in self.items.drain(..) {
+                s.keys += 1;
+                s.bytes += (k.len() + v.len()) as u64;
+            }
+        }
+
+        Ok(s)
+    }
+}
+
+impl Iterator for TreeIter {
+    type Item = Result<(Vec<u8>, Vec<u8>), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.items.is_empty() {
+            match self.fill() {
+                Ok(true) => {},
+                Ok(false) => return None,
+                Err(e) => {
+                    self.pos = self.idx.len();
+                    return Some(Err(e));
+                },
+            }
+        }
+
+        self.items.pop_front().map(Ok)
+    }
+}
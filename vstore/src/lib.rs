@@ -4,8 +4,28 @@ mod index;
 mod error;
 mod commit;
 mod tree;
+mod cache;
+mod branch;
+mod tag;
+mod merge;
+mod gc;
+mod iter;
+mod history;
+mod bundle;
+mod options;
+mod lock;
+pub mod sync;
 pub mod diff;
 
 pub use vstore::VStore;
 pub use error::Error;
+pub use commit::{Commit, CommitMeta, CommitState};
 pub use tree::{Tree, ImmutableTree};
+pub use branch::{Branch, MAIN_BRANCH};
+pub use merge::{Conflict, Resolution, Resolver, MergeStats, TakeOurs, TakeTheirs, NoConflicts};
+pub use gc::{RetentionPolicy, GcStats};
+pub use iter::{TreeIter, TreeSummary};
+pub use history::{KeyChange, KeyEvent};
+pub use bundle::{Bundle, BundleStats};
+pub use options::{StoreOptions, DEFAULT_PREFIX_BITS, MAX_PREFIX_BITS};
+pub use lock::WriterLock;
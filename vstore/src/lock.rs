@@ -0,0 +1,219 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use parking_lot::Mutex;
+use log::debug;
+
+use keyvalue::{KeyValue, WriteBatch};
+use crate::commit::random_id;
+use crate::Error;
+
+pub (crate) const LOCK_KEY: &str = "writer.lock";
+
+/// Holder of a store's writer lock, kept in the "writer.lock" record
+/// while a writer has a version open or is changing the commit state.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct WriterLock {
+    /// Random per open store, tells the holder's own record apart
+    pub owner: u64,
+    pub pid: u32,
+    pub acquired: u64,
+}
+
+impl WriterLock {
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        let buf = rmp_serde::to_vec(self)?;
+        Ok(buf)
+    }
+
+    pub fn from_buf(buf: &[u8]) -> Result<WriterLock, Error> {
+        let l = rmp_serde::from_read_ref(buf)?;
+        Ok(l)
+    }
+
+    pub (crate) fn read(kv: &Arc<Box<dyn KeyValue>>) -> Result<Option<WriterLock>, Error> {
+        match kv.get(0, LOCK_KEY.as_bytes())? {
+            Some(buf) => Ok(Some(WriterLock::from_buf(&buf)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+struct WriterInner {
+    /// Our record while we hold the lock
+    held: Option<Vec<u8>>,
+    /// Writable trees keeping the lock, see `TreeLease`
+    trees: usize,
+    /// Commit state records as last read or written by us, what the next
+    /// write expects to replace
+    state: Vec<Option<Vec<u8>>>,
+}
+
+/// Writer side of a store, shared by its clones. The lock is released
+/// when the last clone is dropped.
+pub (crate) struct Writer {
+    kv: Arc<Box<dyn KeyValue>>,
+    owner: u64,
+    inner: Mutex<WriterInner>,
+}
+
+impl Writer {
+    pub fn new(kv: Arc<Box<dyn KeyValue>>, state: Vec<Option<Vec<u8>>>) -> Self {
+        Writer {
+            kv,
+            owner: random_id(),
+            inner: Mutex::new(WriterInner {
+                held: None,
+                trees: 0,
+                state,
+            }),
+        }
+    }
+
+    /// Takes the lock, failing with `Error::Locked` while another writer
+    /// holds it. Returns false when we held it already.
+    pub fn acquire(&self) -> Result<bool, Error> {
+        let mut inner = self.inner.lock();
+        if inner.held.is_some() {
+            return Ok(false);
+        }
+
+        let rec = WriterLock {
+            owner: self.owner,
+            pid: std::process::id(),
+            acquired: SystemTime::now().duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+        };
+        let buf = rec.to_vec()?;
+
+        let mut batch = WriteBatch::new();
+        batch.expect(0, LOCK_KEY.as_bytes(), None);
+        batch.put(0, LOCK_KEY.as_bytes(), &buf);
+
+        match self.kv.write(batch) {
+            Ok(()) => {},
+            Err(keyvalue::Error::Conflict{..}) => {
+                let holder = WriterLock::read(&self.kv)?;
+                let (pid, since) = holder.map(|h| (h.pid, h.acquired)).unwrap_or((0, 0));
+                return Err(Error::Locked(pid, since));
+            },
+            Err(e) => return Err(e.into()),
+        }
+
+        // Other processes only see it once committed
+        self.kv.sync()?;
+
+        debug!("writer lock acquired {:?}", rec);
+        inner.held = Some(buf);
+        Ok(true)
+    }
+
+    /// Drops our record, unless it was broken and taken over meanwhile.
+    pub fn release(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock();
+        let buf = match inner.held.take() {
+            Some(buf) => buf,
+            None => return Ok(()),
+        };
+
+        let mut batch = WriteBatch::new();
+        batch.expect(0, LOCK_KEY.as_bytes(), Some(&buf));
+        batch.delete(0, LOCK_KEY.as_bytes());
+
+        match self.kv.write(batch) {
+            Ok(()) => {},
+            Err(keyvalue::Error::Conflict{..}) => {
+                debug!("writer lock was broken");
+                return Ok(());
+            },
+            Err(e) => return Err(e.into()),
+        }
+
+        self.kv.sync()?;
+        debug!("writer lock released");
+        Ok(())
+    }
+
+    fn close_tree(&self) -> Result<(), Error> {
+        let last = {
+            let mut inner = self.inner.lock();
+            inner.trees = inner.trees.saturating_sub(1);
+            inner.trees == 0
+        };
+
+        match last {
+            true => self.release(),
+            false => Ok(()),
+        }
+    }
+
+    /// Writable trees currently holding the lock.
+    pub fn open_trees(&self) -> usize {
+        self.inner.lock().trees
+    }
+
+    pub fn state(&self) -> Vec<Option<Vec<u8>>> {
+        self.inner.lock().state.clone()
+    }
+
+    pub fn set_state(&self, state: Vec<Option<Vec<u8>>>) {
+        self.inner.lock().state = state;
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        if let Err(e) = self.release() {
+            debug!("writer lock release failed: {:?}", e);
+        }
+    }
+}
+
+/// Releases the writer lock on drop if it was taken for this guard.
+pub (crate) struct WriteGuard<'a> {
+    writer: &'a Writer,
+    release: bool,
+}
+
+impl<'a> WriteGuard<'a> {
+    pub fn new(writer: &'a Writer, release: bool) -> Self {
+        WriteGuard {
+            writer,
+            release,
+        }
+    }
+}
+
+impl<'a> Drop for WriteGuard<'a> {
+    fn drop(&mut self) {
+        if self.release {
+            if let Err(e) = self.writer.release() {
+                debug!("writer lock release failed: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Held by a writable tree. The writer lock is released once the last
+/// tree holding it is committed, aborted or dropped.
+pub (crate) struct TreeLease {
+    writer: Arc<Writer>,
+}
+
+impl TreeLease {
+    pub fn new(writer: Arc<Writer>) -> Self {
+        writer.inner.lock().trees += 1;
+        TreeLease {
+            writer,
+        }
+    }
+}
+
+impl Drop for TreeLease {
+    fn drop(&mut self) {
+        if let Err(e) = self.writer.close_tree() {
+            debug!("writer lock release failed: {:?}", e);
+        }
+    }
+}
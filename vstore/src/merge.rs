@@ -0,0 +1,144 @@
+
+use std::sync::Arc;
+use std::collections::BTreeSet;
+use log::debug;
+
+use valuepack::Pack;
+
+use crate::Error;
+
+/// A key changed differently on both sides since the base version. Values
+/// are `None` where the key does not exist.
+#[derive(Debug)]
+pub struct Conflict {
+    pub key: Vec<u8>,
+    pub base: Option<Vec<u8>>,
+    pub ours: Option<Vec<u8>>,
+    pub theirs: Option<Vec<u8>>,
+}
+
+pub enum Resolution {
+    Ours,
+    Theirs,
+    /// Custom value, `None` deletes the key
+    Value(Option<Vec<u8>>),
+}
+
+/// Decides conflicting keys during `VStore::merge`. Returning an error
+/// stops the merge.
+pub trait Resolver {
+    fn resolve(&mut self, c: &Conflict) -> Result<Resolution, Error>;
+}
+
+impl<F: FnMut(&Conflict) -> Result<Resolution, Error>> Resolver for F {
+    fn resolve(&mut self, c: &Conflict) -> Result<Resolution, Error> {
+        self(c)
+    }
+}
+
+pub struct TakeOurs;
+
+impl Resolver for TakeOurs {
+    fn resolve(&mut self, _c: &Conflict) -> Result<Resolution, Error> {
+        Ok(Resolution::Ours)
+    }
+}
+
+pub struct TakeTheirs;
+
+impl Resolver for TakeTheirs {
+    fn resolve(&mut self, _c: &Conflict) -> Result<Resolution, Error> {
+        Ok(Resolution::Theirs)
+    }
+}
+
+/// Fails the merge on the first conflict.
+pub struct NoConflicts;
+
+impl Resolver for NoConflicts {
+    fn resolve(&mut self, c: &Conflict) -> Result<Resolution, Error> {
+        Err(Error::MergeConflict(c.key.clone()))
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct MergeStats {
+    /// Partitions changed by theirs that had to be compared key by key
+    pub parts: u64,
+    pub applied: u64,
+    pub conflicts: u64,
+}
+
+/// What to do with one key of a partition changed by theirs
+pub (crate) enum KeyMerge {
+    Keep,
+    /// Take theirs entry, keeping the version that wrote it
+    Apply(Option<(u64, Vec<u8>)>),
+    Conflict(Conflict),
+}
+
+type Entry<'a> = Option<&'a (u64, Vec<u8>)>;
+
+pub (crate) fn entry<'a>(p: &'a Option<Arc<Pack>>, key: &[u8]) -> Entry<'a> {
+    p.as_ref().and_then(|p| p.get(key))
+}
+
+fn value(e: Entry) -> Option<Vec<u8>> {
+    e.map(|(_, v)| v.clone())
+}
+
+/// Entries carry the version that wrote them, equal versions mean the key
+/// was not touched in between.
+fn same_entry(a: Entry, b: Entry) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some((av, _)), Some((bv, _))) => av == bv,
+        _ => false,
+    }
+}
+
+fn same_value(a: Entry, b: Entry) -> bool {
+    match (a, b) {
+        (None, None) => true,
+        (Some((_, av)), Some((_, bv))) => av == bv,
+        _ => false,
+    }
+}
+
+/// Three-way merge of one partition's packs, in key order.
+pub (crate) fn merge_packs(base: &Option<Arc<Pack>>, ours: &Option<Arc<Pack>>, theirs: &Option<Arc<Pack>>)
+    -> Vec<(Vec<u8>, KeyMerge)> {
+
+    let mut keys = BTreeSet::new();
+    for p in [base, theirs].iter() {
+        if let Some(p) = p {
+            keys.extend(p.map.keys().cloned());
+        }
+    }
+
+    let mut items = Vec::new();
+
+    for key in keys {
+        let b = entry(base, &key);
+        let o = entry(ours, &key);
+        let t = entry(theirs, &key);
+
+        let m = if same_entry(b, t) || same_value(o, t) {
+            KeyMerge::Keep
+        }else if same_entry(b, o) {
+            KeyMerge::Apply(t.cloned())
+        }else{
+            debug!("merge conflict key: {:?}", key);
+            KeyMerge::Conflict(Conflict {
+                key: key.clone(),
+                base: value(b),
+                ours: value(o),
+                theirs: value(t),
+            })
+        };
+
+        items.push((key, m));
+    }
+
+    items
+}
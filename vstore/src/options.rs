@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use crate::Error;
+
+/// Used by stores created before options were recorded
+pub const DEFAULT_PREFIX_BITS: usize = 20;
+
+/// Largest index, 16M partitions
+pub const MAX_PREFIX_BITS: usize = 24;
+
+/// Chosen when a store is created and kept in its "header" record.
+/// `prefix_bits` only grows afterwards, see `VStore::reshard`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct StoreOptions {
+    /// Keys are spread over 2^prefix_bits partitions, each with its own pack
+    pub prefix_bits: usize,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            prefix_bits: DEFAULT_PREFIX_BITS,
+        }
+    }
+}
+
+impl StoreOptions {
+    pub fn prefix_bits(mut self, bits: usize) -> Self {
+        self.prefix_bits = bits;
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.prefix_bits < 1 || self.prefix_bits > MAX_PREFIX_BITS {
+            return Err(Error::InvalidOptions(format!("prefix_bits {} not in 1..={}", self.prefix_bits, MAX_PREFIX_BITS)));
+        }
+
+        Ok(())
+    }
+
+    pub fn to_vec(&self) -> Result<Vec<u8>, Error> {
+        let buf = rmp_serde::to_vec(self)?;
+        Ok(buf)
+    }
+
+    pub fn from_buf(buf: &[u8]) -> Result<StoreOptions, Error> {
+        let opts = rmp_serde::from_read_ref(buf)?;
+        Ok(opts)
+    }
+}
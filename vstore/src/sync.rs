@@ -0,0 +1,181 @@
+use std::io::{self, Read, Write};
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use log::debug;
+
+use crate::bundle::{Bundle, BundleStats};
+use crate::commit::Commit;
+use crate::vstore::VStore;
+use crate::Error;
+
+/// The other end of `VStore::push` and `VStore::pull`.
+pub trait Remote {
+    /// Commits of main or a branch from its last commit back to the root.
+    fn log(&mut self, branch: &str) -> Result<Vec<Commit>, Error>;
+
+    /// See `VStore::export_bundle`.
+    fn fetch(&mut self, base: u64, tip: u64) -> Result<Bundle, Error>;
+
+    /// See `VStore::export_shallow`.
+    fn fetch_shallow(&mut self, tip: u64, depth: usize) -> Result<Bundle, Error>;
+
+    /// Imports the bundle and fast-forwards `branch` to its tip.
+    fn send(&mut self, bundle: Bundle, branch: &str) -> Result<BundleStats, Error>;
+}
+
+/// In-process remote
+impl Remote for VStore {
+    fn log(&mut self, branch: &str) -> Result<Vec<Commit>, Error> {
+        let cs = self.commit_state();
+        let head = cs.committed_head(branch)?;
+        let mut commits = cs.chain(0, head)?;
+        commits.reverse();
+        Ok(commits)
+    }
+
+    fn fetch(&mut self, base: u64, tip: u64) -> Result<Bundle, Error> {
+        self.export_bundle(base, tip)
+    }
+
+    fn fetch_shallow(&mut self, tip: u64, depth: usize) -> Result<Bundle, Error> {
+        self.export_shallow(tip, depth)
+    }
+
+    fn send(&mut self, bundle: Bundle, branch: &str) -> Result<BundleStats, Error> {
+        let stats = self.import_bundle(&bundle)?;
+        self.fast_forward(branch, bundle.tip)?;
+        Ok(stats)
+    }
+}
+
+#[derive(Deserialize, Serialize)]
+enum Request {
+    Log(String),
+    Fetch(u64, u64),
+    FetchShallow(u64, u64),
+    Send(Bundle, String),
+}
+
+#[derive(Deserialize, Serialize)]
+enum Response {
+    Log(Vec<Commit>),
+    Bundle(Bundle),
+    Stats(BundleStats),
+    Error(String),
+}
+
+/// Largest frame sent or accepted, a bundle over this has to be moved as a
+/// file instead
+pub const MAX_FRAME_BYTES: u64 = 1 << 30;
+
+// Frames are a big endian u64 length followed by a msgpack message
+fn write_frame<T: Serialize>(w: &mut dyn Write, msg: &T) -> Result<(), Error> {
+    let buf = rmp_serde::to_vec(msg)?;
+    if buf.len() as u64 > MAX_FRAME_BYTES {
+        return Err(Error::Remote(format!("frame of {} bytes over limit", buf.len())));
+    }
+
+    w.write_all(&(buf.len() as u64).to_be_bytes())?;
+    w.write_all(&buf)?;
+    w.flush()?;
+    Ok(())
+}
+
+/// None when the stream ends between frames
+fn read_frame<T: DeserializeOwned>(r: &mut dyn Read) -> Result<Option<T>, Error> {
+    let mut len = [0u8; 8];
+    match r.read_exact(&mut len) {
+        Ok(()) => {},
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+
+    // The length comes from the peer, checked before allocating for it
+    let len = u64::from_be_bytes(len);
+    if len > MAX_FRAME_BYTES {
+        return Err(Error::Remote(format!("frame of {} bytes over limit", len)));
+    }
+
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf)?;
+
+    let msg = rmp_serde::from_read_ref(&buf)?;
+    Ok(Some(msg))
+}
+
+/// Remote reached through a byte stream, such as a pipe to `vst serve` or
+/// a socket, with `serve` on the other end.
+pub struct StreamRemote<R: Read, W: Write> {
+    r: R,
+    w: W,
+}
+
+impl<R: Read, W: Write> StreamRemote<R, W> {
+    pub fn new(r: R, w: W) -> Self {
+        StreamRemote { r, w }
+    }
+
+    fn call(&mut self, req: &Request) -> Result<Response, Error> {
+        write_frame(&mut self.w, req)?;
+
+        match read_frame(&mut self.r)? {
+            Some(Response::Error(msg)) => Err(Error::Remote(msg)),
+            Some(resp) => Ok(resp),
+            None => Err(Error::Remote("connection closed".to_owned())),
+        }
+    }
+}
+
+impl<R: Read, W: Write> Remote for StreamRemote<R, W> {
+    fn log(&mut self, branch: &str) -> Result<Vec<Commit>, Error> {
+        match self.call(&Request::Log(branch.to_owned()))? {
+            Response::Log(commits) => Ok(commits),
+            _ => Err(Error::Remote("unexpected response".to_owned())),
+        }
+    }
+
+    fn fetch(&mut self, base: u64, tip: u64) -> Result<Bundle, Error> {
+        match self.call(&Request::Fetch(base, tip))? {
+            Response::Bundle(b) => Ok(b),
+            _ => Err(Error::Remote("unexpected response".to_owned())),
+        }
+    }
+
+    fn fetch_shallow(&mut self, tip: u64, depth: usize) -> Result<Bundle, Error> {
+        match self.call(&Request::FetchShallow(tip, depth as u64))? {
+            Response::Bundle(b) => Ok(b),
+            _ => Err(Error::Remote("unexpected response".to_owned())),
+        }
+    }
+
+    fn send(&mut self, bundle: Bundle, branch: &str) -> Result<BundleStats, Error> {
+        match self.call(&Request::Send(bundle, branch.to_owned()))? {
+            Response::Stats(stats) => Ok(stats),
+            _ => Err(Error::Remote("unexpected response".to_owned())),
+        }
+    }
+}
+
+/// Answers `StreamRemote` requests for `store` until the stream ends.
+/// Failed requests are reported back, only transport errors end the loop.
+pub fn serve(store: &VStore, r: &mut dyn Read, w: &mut dyn Write) -> Result<(), Error> {
+    let mut store = store.clone();
+
+    while let Some(req) = read_frame::<Request>(r)? {
+        let resp = match req {
+            Request::Log(branch) => store.log(&branch).map(Response::Log),
+            Request::Fetch(base, tip) => store.fetch(base, tip).map(Response::Bundle),
+            Request::FetchShallow(tip, depth) => store.fetch_shallow(tip, depth as usize).map(Response::Bundle),
+            Request::Send(bundle, branch) => store.send(bundle, &branch).map(Response::Stats),
+        };
+
+        let resp = resp.unwrap_or_else(|e| {
+            debug!("serve request failed: {}", e);
+            Response::Error(e.to_string())
+        });
+
+        write_frame(w, &resp)?;
+    }
+
+    Ok(())
+}
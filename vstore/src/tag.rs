@@ -0,0 +1,23 @@
+use std::collections::BTreeMap;
+use crate::Error;
+
+/// Tag names mapped to the committed version they point at.
+pub (crate) type TagTable = BTreeMap<String, u64>;
+
+pub (crate) fn table_to_vec(t: &TagTable) -> Result<Vec<u8>, Error> {
+    let buf = rmp_serde::to_vec(t)?;
+    Ok(buf)
+}
+
+pub (crate) fn table_from_buf(buf: &[u8]) -> Result<TagTable, Error> {
+    let t = rmp_serde::from_read_ref(buf)?;
+    Ok(t)
+}
+
+/// Numeric names would be ambiguous wherever a version is accepted
+pub (crate) fn check_name(name: &str) -> Result<(), Error> {
+    if name.is_empty() || name.parse::<u64>().is_ok() {
+        return Err(Error::InvalidTagName(name.to_owned()));
+    }
+    Ok(())
+}
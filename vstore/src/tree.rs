@@ -1,7 +1,12 @@
 
 use std::sync::Arc;
+use std::collections::BTreeMap;
 use crate::index::Index;
 use crate::commit::Commit;
+use crate::cache::PackCache;
+use crate::iter::{TreeIter, TreeSummary};
+use crate::branch::MAIN_BRANCH;
+use crate::lock::TreeLease;
 
 use log::debug;
 
@@ -10,89 +15,177 @@ use valuepack::Pack;
 
 use crate::Error;
 
+/// Changes to the entries of one partition, `None` deleting the key
+pub (crate) type PartChanges = Vec<(Vec<u8>, Option<(u64, Vec<u8>)>)>;
+
 pub struct Tree {
     kv: Arc<Box<dyn KeyValue>>,
+    packs: PackCache,
     pub (crate) idx: Index,
+    pub (crate) branch: String,
     pub commit: Commit,
+    /// Writer lock share of a writable tree
+    pub (crate) lease: Option<TreeLease>,
 }
 
 impl Tree {
-    pub (crate) fn new(c: Commit, kv: Arc<Box<dyn KeyValue>>) -> Result<Self, Error> {
+    pub (crate) fn new(c: Commit, kv: Arc<Box<dyn KeyValue>>, packs: PackCache, prefix_bits: usize) -> Result<Self, Error> {
         let idx = match Tree::load_index(&c, &kv)? {
             Some(idx) => idx,
-            None => Index::new(20, c.ver),
+            None => Index::new(prefix_bits, c.ver),
         };
 
         idx.set_version(c.ver);
 
         let t = Tree {
             kv,
+            packs,
             idx,
+            branch: MAIN_BRANCH.to_owned(),
             commit: c,
+            lease: None,
         };
 
         Ok(t)
     }
 
-    pub (crate) fn new_readonly(c: Commit, kv: Arc<Box<dyn KeyValue>>) -> Result<Self, Error> {
+    pub (crate) fn new_readonly(c: Commit, kv: Arc<Box<dyn KeyValue>>, packs: PackCache) -> Result<Self, Error> {
         let idx = Tree::load_index(&c, &kv)?.ok_or(Error::IndexNotFound)?;
 
         let t = Tree {
             kv,
+            packs,
             idx,
+            branch: MAIN_BRANCH.to_owned(),
             commit: c,
+            lease: None,
         };
 
         Ok(t)
     }
 
-    fn load_index_at(ver: u16, kv: &Arc<Box<dyn KeyValue>>) -> Result<Option<Index>, Error> {
-        debug!("load index at ver: {}", ver);
-        let index_key_buf = "index".as_bytes();
+    /// Index of the commit, starting from its parent's when it has none
+    /// yet. Deltas are written against the record it was loaded from.
+    fn load_index(c: &Commit, kv: &Arc<Box<dyn KeyValue>>) -> Result<Option<Index>, Error> {
+        if let Some(idx) = Index::load(kv, c.ver)? {
+            return Ok(Some(idx));
+        }
 
-        let idx = match kv.get(ver, index_key_buf)? {
-            Some(buf) => {
-                let idx = Index::new_with_buf(&buf)?;
-                Some(idx)
-            },
-            None => {
-                None
-            }
-        };
+        if c.prev_ver == 0 {
+            return Ok(None);
+        }
+
+        let idx = Index::load(kv, c.prev_ver)?;
+        if let Some(idx) = &idx {
+            idx.layer_on(c.prev_ver);
+        }
 
         Ok(idx)
     }
 
-    fn load_index(c: &Commit, kv: &Arc<Box<dyn KeyValue>>) -> Result<Option<Index>, Error> {
-        let idx = match Tree::load_index_at(c.ver, &kv)? {
-            Some(idx) => Some(idx),
-            None => {
-                if c.prev_ver == 0 {
-                    None
-                }else{
-                    match Tree::load_index_at(c.prev_ver, &kv)? {
-                        Some(idx) => Some(idx),
-                        None => None
-                    }
-                }
-            }
+    fn load_pack(&self, ver: u64, part: u32) -> Result<Option<Arc<Pack>>, Error> {
+        debug!("load pack ver: {} part: {}", ver, part);
+        self.packs.load(&self.kv, ver, part)
+    }
+
+    fn store_pack(&self, part: u32, pack: Pack) -> Result<(), Error> {
+        let buf = pack.to_vec()?;
+
+        debug!("put store kv cver: {} part: {}", self.commit.ver, part);
+        self.kv.put(self.commit.ver, &part.to_be_bytes()[..], &buf)?;
+        self.packs.insert(self.commit.ver, part, Arc::new(pack), buf.len());
+        self.idx.set_part(part, self.commit.ver);
+
+        Ok(())
+    }
+
+    /// Puts and deletes (`None`) entries of one partition with a single
+    /// pack write. Entries keep the version given with their value.
+    pub (crate) fn apply_part(&self, part: u32, changes: PartChanges) -> Result<(), Error> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let p_ver = self.idx.get_prefix_version(part as usize);
+        let mut pack = if p_ver == 0 {
+            Pack::new()
+        }else{
+            let pack = self.load_pack(p_ver, part)?.ok_or(Error::PackNotFound(p_ver, part))?;
+            (*pack).clone()
         };
 
-        Ok(idx)
+        for (key, val) in changes {
+            match val {
+                Some((ver, val)) => pack.put(ver, &key, val),
+                None => {
+                    pack.map.remove(&key);
+                },
+            }
+        }
+
+        self.store_pack(part, pack)
     }
 
-    fn load_pack(&self, ver: u16, part: u32) -> Result<Option<Pack>, Error> {
-        debug!("load pack ver: {} part: {}", ver, part);
+    /// Points every partition at the pack `idx` reads, giving the tree the
+    /// same contents without copying data. With a different partitioning
+    /// the whole index is taken over.
+    pub (crate) fn reset_to(&mut self, idx: &Index) {
+        if idx.prefix_bits() != self.idx.prefix_bits() {
+            self.idx = Index::copy_of(idx, self.commit.ver);
+            return;
+        }
 
-        let key = part.to_be_bytes();
-        let pack = match self.kv.get(ver, &key[..])? {
-            None => None,
-            Some(buf) => {
-                Some(Pack::from_buf(&buf)?)
+        for part in 0..idx.len() {
+            let p_ver = idx.get_prefix_version(part);
+            if self.idx.get_prefix_version(part) != p_ver {
+                self.idx.set_part(part as u32, p_ver);
+            }
+        }
+    }
+
+    /// Rehashes every entry into 2^prefix_bits partitions, written as
+    /// packs of this version. Entries keep the version that wrote them.
+    /// Partitions can only be split.
+    pub (crate) fn reshard(&mut self, prefix_bits: usize) -> Result<(), Error> {
+        let old = self.idx.clone();
+        if prefix_bits <= old.prefix_bits() {
+            return Err(Error::InvalidOptions(format!("prefix_bits {} not above {}", prefix_bits, old.prefix_bits())));
+        }
+
+        self.idx = Index::new(prefix_bits, self.commit.ver);
+
+        // Partition p splits into p*2^k..(p+1)*2^k for k added bits, all at
+        // or above p, so going down never overwrites a pack of this version
+        // not read yet
+        for part in (0..old.len() as u32).rev() {
+            let p_ver = old.get_prefix_version(part as usize);
+            if p_ver == 0 {
+                continue;
             }
-        };
 
-        Ok(pack)
+            let pack = self.load_pack(p_ver, part)?.ok_or(Error::PackNotFound(p_ver, part))?;
+            if p_ver == self.commit.ver {
+                self.kv.delete(p_ver, &part.to_be_bytes()[..])?;
+            }
+
+            let mut split: BTreeMap<u32, Pack> = BTreeMap::new();
+            for (key, (ver, val)) in pack.map.iter() {
+                let (_, p, _) = self.idx.get_part(key);
+                split.entry(p).or_insert_with(Pack::new).put(*ver, key, val.clone());
+            }
+
+            debug!("reshard part: {} into: {}", part, split.len());
+
+            for (p, pack) in split {
+                self.store_pack(p, pack)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn branch(&self) -> &str {
+        &self.branch
     }
 
     pub fn put_str(&self, key: &str, val: &[u8]) -> Result<(), Error> {
@@ -109,15 +202,11 @@ impl Tree {
             Pack::new()
         }else{
             let pack = self.load_pack(p_ver, p)?.ok_or(Error::PackNotFound(p_ver, p))?;
-            pack
+            (*pack).clone()
         };
 
         pack.put(self.commit.ver, key, Vec::from(val));
-        let buf = pack.to_vec()?;
-
-        debug!("put store kv cver: {} pver: {} part: {}", self.commit.ver, p_ver, p);
-        self.kv.put(self.commit.ver, &p.to_be_bytes()[..], &buf)?;
-        self.idx.set_part(p, self.commit.ver);
+        self.store_pack(p, pack)?;
         debug!("index part set {:?}", self.idx.get_part(key));
 
         Ok(())
@@ -140,14 +229,11 @@ impl Tree {
             None => {
                 return Ok(())
             },
-            Some(mut pack) => {
+            Some(pack) => {
+                let mut pack = (*pack).clone();
                 pack.map.remove(key);
 
-                let buf = pack.to_vec()?;
-
-                debug!("put store kv cver: {} pver: {} part: {}", self.commit.ver, p_ver, p);
-                self.kv.put(self.commit.ver, &p.to_be_bytes()[..], &buf)?;
-                self.idx.set_part(p, self.commit.ver);
+                self.store_pack(p, pack)?;
                 debug!("index part set {:?}", self.idx.get_part(key));
             }
         }
@@ -180,6 +266,20 @@ impl Tree {
         Ok(val)
     }
 
+    /// Iterates over all keys and values, see `TreeIter`.
+    pub fn iter(&self) -> TreeIter {
+        TreeIter::new(self.kv.clone(), self.packs.clone(), self.idx.clone(), None)
+    }
+
+    /// Iterates over the keys starting with `prefix`. Keys are spread over
+    /// partitions by hash, so every pack is still read.
+    pub fn iter_prefix(&self, prefix: &[u8]) -> TreeIter {
+        TreeIter::new(self.kv.clone(), self.packs.clone(), self.idx.clone(), Some(prefix.to_owned()))
+    }
+
+    pub fn summary(&self) -> Result<TreeSummary, Error> {
+        self.iter().summary()
+    }
 }
 
 pub struct ImmutableTree {
@@ -194,4 +294,16 @@ impl ImmutableTree {
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Error> {
         self.t.get(key)
     }
-}
\ No newline at end of file
+
+    pub fn iter(&self) -> TreeIter {
+        self.t.iter()
+    }
+
+    pub fn iter_prefix(&self, prefix: &[u8]) -> TreeIter {
+        self.t.iter_prefix(prefix)
+    }
+
+    pub fn summary(&self) -> Result<TreeSummary, Error> {
+        self.t.summary()
+    }
+}
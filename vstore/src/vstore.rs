@@ -1,27 +1,54 @@
 use std::sync::Arc;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryInto;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use log::debug;
+use parking_lot::RwLock;
 
-use keyvalue::KeyValue;
+use keyvalue::{KeyValue, WriteBatch, Scan};
+use valuepack::Pack;
+use keyvalue::cache::CacheStats;
 use crate::tree::{Tree, ImmutableTree};
-use crate::commit::{CommitState};
+use crate::commit::{Commit, CommitState, CommitMeta};
+use crate::branch::{Branch, MAIN_BRANCH};
 use crate::diff::DiffIter;
 use crate::index::Index;
+use crate::merge::{self, KeyMerge, Resolution, Resolver, MergeStats};
+use crate::gc::{RetentionPolicy, GcStats};
+use crate::bundle::{Bundle, BundleIndex, BundlePack, BundleStats};
+use crate::sync::Remote;
+use crate::history::{self, KeyChange, KeyEvent, KeyState};
+use crate::options::StoreOptions;
+use crate::lock::{Writer, WriterLock, WriteGuard, TreeLease, LOCK_KEY};
+use crate::cache::{PackCache, DEFAULT_PACK_CACHE_BYTES};
 use crate::Error;
 
 #[derive(Clone)]
 pub struct VStore {
     kv: Arc<Box<dyn KeyValue>>,
     cstate: CommitState,
+    packs: PackCache,
+    opts: Arc<RwLock<StoreOptions>>,
+    writer: Arc<Writer>,
 }
 
+/// Records holding the commit state, replaced together
+const STATE_KEYS: [&str; 3] = ["commits", "branches", "tags"];
+
 impl VStore {
     pub fn create(kv: Arc<Box<dyn KeyValue>>) -> Result<Self, Error>  {
+        VStore::create_with(kv, StoreOptions::default())
+    }
+
+    pub fn create_with(kv: Arc<Box<dyn KeyValue>>, opts: StoreOptions) -> Result<Self, Error>  {
+        opts.validate()?;
+
         if VStore::check_init(&kv)? {
             return Err(Error::AlreadyInitedError);
         }
 
-        let v= VStore::first_init(&kv)?;
+        let v= VStore::first_init(&kv, opts)?;
         v.mark_init()?;
         kv.sync()?;
         debug!("commit state at init: {:?}", v.cstate);
@@ -38,30 +65,70 @@ impl VStore {
     }
 
     fn load(kv: &Arc<Box<dyn KeyValue>>) -> Result<Self, Error> {
-        let buf = kv.get(0, "commits".as_bytes())?
-            .ok_or(Error::InitError(format!("Commits key not found")))?;
+        let state = VStore::read_state(kv)?;
+        let cstate = CommitState::default();
+        VStore::load_state(&cstate, &state)?;
 
-        let cstate = CommitState::from_buf(&buf)?;
+        let opts = VStore::read_options(kv)?;
 
         let v = VStore {
             kv: kv.clone(),
             cstate,
+            packs: PackCache::new(DEFAULT_PACK_CACHE_BYTES),
+            opts: Arc::new(RwLock::new(opts)),
+            writer: Arc::new(Writer::new(kv.clone(), state)),
         };
 
         Ok(v)
     }
 
-    fn first_init(kv: &Arc<Box<dyn KeyValue>>) -> Result<Self, Error> {
-        debug!("first time initialization");
+    fn read_options(kv: &Arc<Box<dyn KeyValue>>) -> Result<StoreOptions, Error> {
+        let opts = match kv.get(0, "header".as_bytes())? {
+            Some(buf) => StoreOptions::from_buf(&buf)?,
+            None => StoreOptions::default(),
+        };
+        opts.validate()?;
+
+        Ok(opts)
+    }
+
+    fn read_state(kv: &Arc<Box<dyn KeyValue>>) -> Result<Vec<Option<Vec<u8>>>, Error> {
+        STATE_KEYS.iter()
+            .map(|key| Ok(kv.get(0, key.as_bytes())?))
+            .collect()
+    }
+
+    fn load_state(cstate: &CommitState, state: &[Option<Vec<u8>>]) -> Result<(), Error> {
+        let buf = state[0].as_ref()
+            .ok_or(Error::InitError(format!("Commits key not found")))?;
+        cstate.reload(buf)?;
+
+        if let Some(buf) = &state[1] {
+            cstate.load_branches(buf)?;
+        }
+
+        if let Some(buf) = &state[2] {
+            cstate.load_tags(buf)?;
+        }
+
+        Ok(())
+    }
+
+    fn first_init(kv: &Arc<Box<dyn KeyValue>>, opts: StoreOptions) -> Result<Self, Error> {
+        debug!("first time initialization {:?}", opts);
 
         let cstate = CommitState::default();
         let cstate_buf = cstate.to_vec()?;
 
         kv.put(0, "commits".as_bytes(), &cstate_buf)?;
+        kv.put(0, "header".as_bytes(), &opts.to_vec()?)?;
         
         let v = VStore {
             kv: kv.clone(),
             cstate,
+            packs: PackCache::new(DEFAULT_PACK_CACHE_BYTES),
+            opts: Arc::new(RwLock::new(opts)),
+            writer: Arc::new(Writer::new(kv.clone(), vec![Some(cstate_buf), None, None])),
         };
 
         Ok(v)
@@ -92,15 +159,102 @@ impl VStore {
     }
 
     fn write_commit_state(&self) -> Result<(), Error> {
+        self.write_with_state(WriteBatch::new())
+    }
+
+    /// Writes `batch` together with the commit state. Fails with
+    /// `Error::ConcurrentWrite`, writing nothing, when another writer
+    /// replaced the state since this store last read or wrote it.
+    fn write_with_state(&self, mut batch: WriteBatch) -> Result<(), Error> {
         debug!("writing commit state {:?}", self.cstate);
-        let buf = self.cstate.to_vec()?;
-        self.kv.put_str(0, "commits", &buf)?;
+
+        let state = vec![
+            Some(self.cstate.to_vec()?),
+            Some(self.cstate.branches_to_vec()?),
+            Some(self.cstate.tags_to_vec()?),
+        ];
+
+        for ((key, old), new) in STATE_KEYS.iter().zip(self.writer.state()).zip(state.iter()) {
+            batch.expect(0, key.as_bytes(), old.as_deref());
+            batch.put(0, key.as_bytes(), new.as_deref().unwrap_or_default());
+        }
+
+        match self.kv.write(batch) {
+            Ok(()) => {},
+            Err(keyvalue::Error::Conflict{..}) => {
+                // Our changes are lost, what is in memory follows the store
+                self.refresh()?;
+                return Err(Error::ConcurrentWrite);
+            },
+            Err(e) => return Err(e.into()),
+        }
+
+        self.writer.set_state(state);
         Ok(())
     }
 
+    /// Takes the writer lock for one change, unless this store holds it
+    /// already. The commit state is read again when another writer may
+    /// have changed it.
+    fn write_guard(&self) -> Result<WriteGuard<'_>, Error> {
+        let taken = self.writer.acquire()?;
+        let guard = WriteGuard::new(&self.writer, taken);
+
+        if taken {
+            self.refresh()?;
+        }
+
+        Ok(guard)
+    }
+
+    fn refresh(&self) -> Result<(), Error> {
+        let state = VStore::read_state(&self.kv)?;
+        if state != self.writer.state() {
+            debug!("commit state changed by another writer");
+            VStore::load_state(&self.cstate, &state)?;
+            self.writer.set_state(state);
+
+            // Packs of an open version are rewritten under the same key
+            self.packs.clear();
+
+            // The header changes with the commit of a reshard
+            *self.opts.write() = VStore::read_options(&self.kv)?;
+        }
+
+        Ok(())
+    }
+
+    /// Holder of the writer lock, if any.
+    pub fn writer_lock(&self) -> Result<Option<WriterLock>, Error> {
+        WriterLock::read(&self.kv)
+    }
+
+    /// Removes the writer lock whoever holds it, for locks left behind
+    /// by a writer that died. Returns the holder.
+    pub fn break_lock(&self) -> Result<Option<WriterLock>, Error> {
+        let holder = WriterLock::read(&self.kv)?;
+        self.kv.delete(0, LOCK_KEY.as_bytes())?;
+        self.kv.sync()?;
+        Ok(holder)
+    }
+
     pub fn writable(&self) -> Result<Tree, Error> {
-        debug!("(pre) writable commit {:?}", self.cstate);
-        let c = self.cstate.open()?;
+        self.writable_branch(MAIN_BRANCH)
+    }
+
+    /// Writable tree on the open version of a branch, opening a new
+    /// version on top of the branch head when there is none. Takes the
+    /// writer lock, held until every writable tree of the store is
+    /// committed, aborted or dropped.
+    pub fn writable_branch(&self, name: &str) -> Result<Tree, Error> {
+        let taken = self.writer.acquire()?;
+        let lease = TreeLease::new(self.writer.clone());
+        if taken {
+            self.refresh()?;
+        }
+
+        debug!("(pre) writable commit {} {:?}", name, self.cstate);
+        let c = self.cstate.open_branch(name)?;
         debug!("(post) writable commit {:?}", self.cstate);
         debug!("writable commit open: {:?}", c);
 
@@ -108,27 +262,29 @@ impl VStore {
         self.kv.sync()?;
 
 
-        let t = Tree::new(c.clone(), self.kv.clone())?;
+        let mut t = Tree::new(c.clone(), self.kv.clone(), self.packs.clone(), self.options().prefix_bits)?;
+        t.branch = name.to_owned();
+        t.lease = Some(lease);
         Ok(t)
     }
 
-    pub fn read_only(&self, ver: u16) -> Result<Tree, Error> {
+    pub fn read_only(&self, ver: u64) -> Result<Tree, Error> {
         let c = self.cstate.get_commit(ver).ok_or(Error::CommitNotFound(ver))?;
 
-        let t = Tree::new_readonly(c, self.kv.clone())?;
+        let t = Tree::new_readonly(c, self.kv.clone(), self.packs.clone())?;
         Ok(t)
     }
 
     pub fn sync_tree(&self, t: &Tree) -> Result<(), Error> {
-        debug!("syncing tree");
-        let buf = t.idx.to_vec()?;
+        self.sync_tree_with(t, WriteBatch::new())
+    }
 
+    fn sync_tree_with(&self, t: &Tree, mut batch: WriteBatch) -> Result<(), Error> {
+        debug!("syncing tree");
         debug!("syncing index");
-        self.kv.put(t.commit.ver, "index".as_bytes(), &buf)?;
+        batch.put(t.commit.ver, "index".as_bytes(), &t.idx.to_record(&self.kv)?);
 
-        debug!("syncing commit state {:?}", self.cstate);
-        let buf = self.cstate.to_vec()?;
-        self.kv.put(0, "commits".as_bytes(), &buf)?;
+        self.write_with_state(batch)?;
 
         debug!("kv sync");
         self.kv.sync()?;
@@ -136,43 +292,1034 @@ impl VStore {
     }
 
     pub fn commit(&self, t: Tree) -> Result<(), Error> {
-        debug!("commiting start");
+        self.commit_with(t, CommitMeta::default())
+    }
+
+    /// Commits the tree's version recording `meta`, its timestamp is set
+    /// to the current time.
+    pub fn commit_with(&self, t: Tree, mut meta: CommitMeta) -> Result<(), Error> {
+        debug!("commiting start {}", t.branch);
 
-        self.cstate.commit();
+        meta.timestamp = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
 
-        self.sync_tree(&t)?;
+        self.cstate.set_meta(t.commit.ver, meta)?;
+        self.cstate.commit_branch(&t.branch)?;
+
+        // A resharded tree records its partition count as the store's
+        let mut batch = WriteBatch::new();
+        let mut opts = self.options();
+        let resharded = t.idx.prefix_bits() > opts.prefix_bits;
+        if resharded {
+            opts.prefix_bits = t.idx.prefix_bits();
+            batch.put(0, "header".as_bytes(), &opts.to_vec()?);
+        }
+
+        self.sync_tree_with(&t, batch)?;
+        if resharded {
+            *self.opts.write() = opts;
+        }
 
         debug!("commiting done");
         Ok(())
     }
 
+    /// Throws away the tree's open version: every record written under it
+    /// and its commit. The branch head goes back to the version it was
+    /// opened from.
+    pub fn abort(&self, t: Tree) -> Result<GcStats, Error> {
+        debug!("abort ver: {} branch: {}", t.commit.ver, t.branch);
+
+        let _guard = self.write_guard()?;
+        self.cstate.abort_branch(&t.branch, t.commit.ver)?;
+
+        let stats = self.drop_versions(&[t.commit.ver])?;
+        self.packs.clear();
+
+        Ok(stats)
+    }
+
+    /// Commits a new version on main with the contents of `ver`, see
+    /// `revert_branch`. Returns the new version.
+    pub fn revert_to(&self, ver: u64) -> Result<u64, Error> {
+        self.revert_branch(MAIN_BRANCH, ver)
+    }
+
+    /// Commits a new version on a branch with the contents of the committed
+    /// version `ver`. Its index reads the packs of `ver`, nothing else is
+    /// copied. The branch must have no open version.
+    pub fn revert_branch(&self, name: &str, ver: u64) -> Result<u64, Error> {
+        debug!("revert {} to ver: {}", name, ver);
+
+        let idx = self.committed_index(ver)?;
+
+        let (_, b) = self.cstate.branches().into_iter()
+            .find(|(n, _)| n == name)
+            .ok_or(Error::BranchNotFound(name.to_owned()))?;
+        if b.open_ver != 0 {
+            return Err(Error::VersionInUse(b.open_ver));
+        }
+
+        let mut t = self.writable_branch(name)?;
+        t.reset_to(&idx);
+
+        let new_ver = t.commit.ver;
+        let meta = CommitMeta::new(&format!("revert to {}", ver))
+            .prop("revert", &ver.to_string());
+        self.commit_with(t, meta)?;
+
+        Ok(new_ver)
+    }
+
     pub fn commit_state<'a>(&'a self) -> &'a CommitState {
         &self.cstate
     }
 
-    fn load_index_at(&self, ver: u16)-> Result<Option<Index>, Error> {
-        debug!("load index at ver: {}", ver);
-        let index_key_buf = "index".as_bytes();
+    pub fn options(&self) -> StoreOptions {
+        self.opts.read().clone()
+    }
 
-        let idx = match self.kv.get(ver, index_key_buf)? {
-            Some(buf) => {
-                let idx = Index::new_with_buf(&buf)?;
-                Some(idx)
-            },
-            None => {
-                None
+    /// Starts a branch at a committed version.
+    pub fn create_branch(&self, name: &str, ver: u64) -> Result<(), Error> {
+        let _guard = self.write_guard()?;
+        self.cstate.create_branch(name, ver)?;
+        self.write_commit_state()?;
+        self.kv.sync()?;
+        Ok(())
+    }
+
+    /// Removes a branch. Versions committed on it stay readable.
+    pub fn delete_branch(&self, name: &str) -> Result<(), Error> {
+        let _guard = self.write_guard()?;
+        self.cstate.delete_branch(name)?;
+        self.write_commit_state()?;
+        self.kv.sync()?;
+        Ok(())
+    }
+
+    pub fn branches(&self) -> Vec<(String, Branch)> {
+        self.cstate.branches()
+    }
+
+    /// Names a committed version. Tags are immutable, delete and create
+    /// again to move one.
+    pub fn create_tag(&self, name: &str, ver: u64) -> Result<(), Error> {
+        let _guard = self.write_guard()?;
+        self.cstate.create_tag(name, ver)?;
+        self.write_commit_state()?;
+        self.kv.sync()?;
+        Ok(())
+    }
+
+    pub fn delete_tag(&self, name: &str) -> Result<(), Error> {
+        let _guard = self.write_guard()?;
+        self.cstate.delete_tag(name)?;
+        self.write_commit_state()?;
+        self.kv.sync()?;
+        Ok(())
+    }
+
+    pub fn tags(&self) -> Vec<(String, u64)> {
+        self.cstate.tags()
+    }
+
+    pub fn resolve_tag(&self, name: &str) -> Result<u64, Error> {
+        self.cstate.resolve_tag(name).ok_or(Error::TagNotFound(name.to_owned()))
+    }
+
+    /// Version from a version number or a tag name.
+    pub fn resolve_version(&self, spec: &str) -> Result<u64, Error> {
+        match spec.parse::<u64>() {
+            Ok(ver) => Ok(ver),
+            Err(_) => self.resolve_tag(spec),
+        }
+    }
+
+    /// Hit and miss counters of the decoded pack cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.packs.stats()
+    }
+
+    /// Bounds the decoded pack cache to `bytes` of encoded pack size.
+    pub fn set_cache_capacity(&self, bytes: usize) {
+        self.packs.set_capacity(bytes);
+    }
+
+    /// Bundles the commits after `base` (0 for the root) up to `tip` with
+    /// every pack they read that `base` does not.
+    pub fn export_bundle(&self, base: u64, tip: u64) -> Result<Bundle, Error> {
+        let commits = self.cstate.chain(base, tip)?;
+        let base_idx = match base {
+            0 => None,
+            base => Some(self.committed_index(base)?),
+        };
+
+        let mut b = self.bundle_commits(base, base_idx, tip, commits)?;
+
+        // From the root of a shallow clone the bundle is itself shallow
+        if let Some(prev_ver) = b.commits.first().map(|c| c.prev_ver) {
+            if base == 0 && prev_ver != 0 {
+                b.base = prev_ver;
+                b.shallow = true;
+            }
+        }
+
+        Ok(b)
+    }
+
+    /// Bundles the last `depth` commits up to `tip` with every pack they
+    /// read, including packs written by older versions.
+    pub fn export_shallow(&self, tip: u64, depth: usize) -> Result<Bundle, Error> {
+        let mut commits = self.cstate.chain(0, tip)?;
+        let skip = commits.len().saturating_sub(depth.max(1));
+        commits.drain(..skip);
+
+        let base = commits.first().map(|c| c.prev_ver).unwrap_or(0);
+        let mut b = self.bundle_commits(base, None, tip, commits)?;
+        b.shallow = base != 0;
+        Ok(b)
+    }
+
+    fn bundle_commits(&self, base: u64, base_idx: Option<Index>, tip: u64, commits: Vec<Commit>) -> Result<Bundle, Error> {
+        let mut indexes = Vec::new();
+        let mut wanted = BTreeSet::new();
+
+        // The first index goes whole so it does not depend on how the
+        // receiver stores base, the rest as deltas against the one before
+        let mut prev: Option<(u64, Index, u32)> = None;
+
+        for c in commits.iter() {
+            let idx = self.load_index_at(c.ver)?.ok_or(Error::IndexNotFound)?;
+
+            for part in 0..idx.len() {
+                let pver = idx.get_prefix_version(part);
+                let in_base = base_idx.as_ref()
+                    .map(|b| b.len() == idx.len() && b.get_prefix_version(part) == pver)
+                    .unwrap_or(false);
+
+                if pver != 0 && !in_base {
+                    wanted.insert((pver, part as u32));
+                }
+            }
+
+            let (buf, depth) = match &prev {
+                Some((pver, pidx, pdepth)) => idx.delta_vec(pidx, *pver, *pdepth)?,
+                None => (idx.to_vec()?, 0),
+            };
+
+            indexes.push(BundleIndex { ver: c.ver, buf });
+            prev = Some((c.ver, idx, depth));
+        }
+
+        let mut packs = Vec::new();
+        for (ver, part) in wanted {
+            let buf = self.kv.get(ver, &part.to_be_bytes()[..])
+                .map_err(|e| Error::from_pack_read(e, ver, part))?
+                .ok_or(Error::PackNotFound(ver, part))?;
+            packs.push(BundlePack { ver, part, buf });
+        }
+
+        debug!("bundle {}..{} commits: {} packs: {}", base, tip, commits.len(), packs.len());
+
+        Ok(Bundle {
+            base,
+            tip,
+            commits,
+            indexes,
+            packs,
+            shallow: false,
+        })
+    }
+
+    /// Verifies a bundle against this store and adds its commits, indexes
+    /// and packs in one batch. Branch heads are left alone, see
+    /// `fast_forward`.
+    pub fn import_bundle(&self, b: &Bundle) -> Result<BundleStats, Error> {
+        let _guard = self.write_guard()?;
+        // Only an empty store can start its history from a shallow bundle
+        let shallow = b.base != 0 && self.cstate.get_commit(b.base).is_none();
+        if shallow && !(b.shallow && self.cstate.versions().is_empty()) {
+            return Err(Error::CommitNotFound(b.base));
+        }
+
+        let retired: HashSet<u64> = self.cstate.retired().into_iter().collect();
+        let bundled: HashSet<(u64, u32)> = b.packs.iter().map(|p| (p.ver, p.part)).collect();
+
+        let records: HashMap<u64, &Vec<u8>> = b.indexes.iter().map(|i| (i.ver, &i.buf)).collect();
+        let get_record = |ver: u64| match records.get(&ver) {
+            Some(buf) => Ok(Some(buf.to_vec())),
+            None => Ok(self.kv.get(ver, "index".as_bytes())?),
+        };
+
+        for c in b.commits.iter() {
+            if !records.contains_key(&c.ver) {
+                return Err(Error::BundleInvalid(format!("no index for v={}", c.ver)));
+            }
+            let idx = Index::load_with(c.ver, get_record)?.ok_or(Error::IndexNotFound)?;
+
+            // A version both stores have must be the same commit, not one
+            // each side created on its own
+            if self.cstate.get_commit(c.ver).is_some() {
+                let same = self.load_index_at(c.ver)?
+                    .map(|own| own.same_parts(&idx))
+                    .unwrap_or(false);
+                if !same {
+                    return Err(Error::BundleConflict(c.ver));
+                }
+
+                for p in b.packs.iter().filter(|p| p.ver == c.ver) {
+                    if self.kv.get(p.ver, &p.part.to_be_bytes()[..])?.as_ref() != Some(&p.buf) {
+                        return Err(Error::BundleConflict(c.ver));
+                    }
+                }
+            }
+
+            for part in 0..idx.len() {
+                let pver = idx.get_prefix_version(part);
+                let present = pver == 0
+                    || bundled.contains(&(pver, part as u32))
+                    || self.cstate.get_commit(pver).is_some()
+                    || retired.contains(&pver);
+
+                if !present {
+                    return Err(Error::PackNotFound(pver, part as u32));
+                }
+            }
+        }
+
+        let mut stats = BundleStats {
+            commits: self.cstate.import_commits(&b.commits, if shallow { b.base } else { 0 })?,
+            ..Default::default()
+        };
+
+        if shallow {
+            let pack_vers: BTreeSet<u64> = b.packs.iter()
+                .map(|p| p.ver)
+                .filter(|ver| self.cstate.get_commit(*ver).is_none())
+                .collect();
+            let pack_vers: Vec<u64> = pack_vers.into_iter().collect();
+            self.cstate.set_shallow(b.base, &pack_vers);
+        }
+        stats.packs = b.packs.len() as u64;
+        stats.bytes = b.size();
+
+        let mut batch = WriteBatch::new();
+        for p in b.packs.iter() {
+            batch.put(p.ver, &p.part.to_be_bytes()[..], &p.buf);
+        }
+        for i in b.indexes.iter() {
+            batch.put(i.ver, "index".as_bytes(), &i.buf);
+        }
+
+        self.write_with_state(batch)?;
+        self.kv.sync()?;
+
+        debug!("imported bundle {:?}", stats);
+        Ok(stats)
+    }
+
+    /// Moves main or a branch forward to a descendant of its head.
+    pub fn fast_forward(&self, name: &str, ver: u64) -> Result<(), Error> {
+        let _guard = self.write_guard()?;
+        self.cstate.fast_forward(name, ver)?;
+        self.write_commit_state()?;
+        self.kv.sync()?;
+        Ok(())
+    }
+
+    /// Brings `branch` up to the remote's branch, transferring the commits
+    /// after this store's head and the packs they changed.
+    pub fn pull(&self, remote: &mut dyn Remote, branch: &str) -> Result<BundleStats, Error> {
+        let log = remote.log(branch)?;
+        let tip = match log.first() {
+            Some(c) => c.ver,
+            None => return Ok(BundleStats::default()),
+        };
+
+        let head = self.cstate.committed_head(branch)?;
+        let local = self.cstate.chain(0, head)?;
+        if local.contains(&log[0]) {
+            debug!("pull {} up to date at {}", branch, head);
+            return Ok(BundleStats::default());
+        }
+
+        if head != 0 && !log.iter().any(|c| Some(c) == local.last()) {
+            return Err(Error::Diverged(branch.to_owned()));
+        }
+
+        let b = remote.fetch(head, tip)?;
+        let stats = self.import_bundle(&b)?;
+        self.fast_forward(branch, tip)?;
+
+        Ok(stats)
+    }
+
+    /// Brings the remote's `branch` up to this store's, see `pull`.
+    pub fn push(&self, remote: &mut dyn Remote, branch: &str) -> Result<BundleStats, Error> {
+        let log = remote.log(branch)?;
+        let remote_head = log.first().map(|c| c.ver).unwrap_or(0);
+
+        let head = self.cstate.committed_head(branch)?;
+        let local = self.cstate.chain(0, head)?;
+        if local.is_empty() || log.contains(&local[local.len() - 1]) {
+            debug!("push {} up to date at {}", branch, remote_head);
+            return Ok(BundleStats::default());
+        }
+
+        if remote_head != 0 && !local.contains(&log[0]) {
+            return Err(Error::Diverged(branch.to_owned()));
+        }
+
+        let b = self.export_bundle(remote_head, head)?;
+        remote.send(b, branch)
+    }
+
+    /// Creates a store over `kv` holding `branch` of the remote, limited to
+    /// its last `depth` commits when given. The branch, or main, points at
+    /// the remote's head.
+    pub fn clone_from(kv: Arc<Box<dyn KeyValue>>, remote: &mut dyn Remote, branch: &str, depth: Option<usize>) -> Result<VStore, Error> {
+        let v = VStore::create(kv)?;
+
+        let tip = match remote.log(branch)?.first() {
+            Some(c) => c.ver,
+            None => return Ok(v),
+        };
+
+        let b = match depth {
+            Some(depth) => remote.fetch_shallow(tip, depth)?,
+            None => remote.fetch(0, tip)?,
+        };
+
+        v.import_bundle(&b)?;
+
+        if branch == MAIN_BRANCH {
+            v.fast_forward(branch, tip)?;
+        }else{
+            v.create_branch(branch, tip)?;
+        }
+
+        Ok(v)
+    }
+
+    /// Commits that created, modified or deleted `key`, walking back from
+    /// `from` through `prev_ver`, newest first. Partitions whose version did
+    /// not change between two commits are not read again.
+    pub fn key_history(&self, key: &[u8], from: u64) -> Result<Vec<KeyEvent>, Error> {
+        let mut states: Vec<(Commit, KeyState)> = Vec::new();
+        let mut last: Option<(u64, KeyState)> = None;
+        let mut ver = from;
+
+        while let Some(c) = self.cstate.get_commit(ver) {
+            let t = self.read_only(c.ver)?;
+            let (part_ver, part, _) = t.idx.get_part(key);
+
+            let state = match &last {
+                Some((last_part_ver, state)) if *last_part_ver == part_ver => state.clone(),
+                _ => {
+                    self.load_part(part_ver, part)?
+                        .and_then(|p| p.get(key).cloned())
+                },
+            };
+
+            ver = c.prev_ver;
+            last = Some((part_ver, state.clone()));
+            states.push((c, state));
+        }
+
+        // Where a shallow history starts the oldest state has no known
+        // parent, its entry version still tells whether that commit wrote it
+        let cut = ver != 0;
+
+        let mut events = Vec::new();
+        for (i, (c, after)) in states.iter().enumerate() {
+            if cut && i + 1 == states.len() {
+                if let Some((ever, val)) = after {
+                    if *ever == c.ver {
+                        events.push(KeyEvent {
+                            commit: c.clone(),
+                            change: KeyChange::Modified(val.clone()),
+                        });
+                    }
+                }
+                break;
+            }
+
+            let before = states.get(i + 1).map(|(_, s)| s.clone()).unwrap_or(None);
+
+            if let Some(change) = history::change(&before, after) {
+                events.push(KeyEvent {
+                    commit: c.clone(),
+                    change,
+                });
             }
+        }
+
+        Ok(events)
+    }
+
+    /// Drops commits expired under `policy` together with the indexes and
+    /// packs only they reference. Tags on dropped commits are removed.
+    pub fn gc(&self, policy: &RetentionPolicy) -> Result<GcStats, Error> {
+        let _guard = self.write_guard()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        let vers = self.cstate.expired(policy, now);
+        debug!("gc expired versions: {:?}", vers);
+
+        self.drop_versions(&vers)
+    }
+
+    /// Collapses the commits between `from` and its descendant `to`, which
+    /// then follows `from` directly. Packs `to` reads from the dropped
+    /// versions are moved under `to` so nothing else keeps them alive; tags
+    /// on the dropped versions are removed. Fails with
+    /// `Error::VersionInUse` while a writable tree descending from the
+    /// dropped versions is open, it still reads the packs being moved.
+    pub fn squash(&self, from: u64, to: u64) -> Result<GcStats, Error> {
+        let _guard = self.write_guard()?;
+        let vers = self.cstate.squash_range(from, to)?;
+        debug!("squash {}..{} dropping {:?}", from, to, vers);
+
+        if vers.is_empty() {
+            return Ok(GcStats::default());
+        }
+
+        if self.writer.open_trees() > 0 {
+            for open_ver in self.cstate.open_versions() {
+                if vers.iter().any(|ver| self.cstate.common_ancestor(open_ver, *ver) == Some(*ver)) {
+                    return Err(Error::VersionInUse(open_ver));
+                }
+            }
+        }
+
+        let dropped: HashSet<u64> = vers.iter().cloned().collect();
+        let to_idx = self.committed_index(to)?;
+        let mut moved = HashSet::new();
+        let mut batch = WriteBatch::new();
+        let mut rewritten = HashMap::new();
+
+        for part in 0..to_idx.len() {
+            let pver = to_idx.get_prefix_version(part);
+            if !dropped.contains(&pver) {
+                continue;
+            }
+
+            let part = part as u32;
+            let buf = self.kv.get(pver, &part.to_be_bytes()[..])
+                .map_err(|e| Error::from_pack_read(e, pver, part))?
+                .ok_or(Error::PackNotFound(pver, part))?;
+
+            batch.put(to, &part.to_be_bytes()[..], &buf);
+            to_idx.set_part(part, to);
+            moved.insert((pver, part));
+        }
+
+        rewritten.insert(to, to_idx);
+
+        // Later versions sharing a moved pack read the copy under `to`
+        for ver in self.cstate.versions() {
+            if ver == to || dropped.contains(&ver) {
+                continue;
+            }
+
+            let idx = match self.load_index_at(ver)? {
+                Some(idx) => idx,
+                None => continue,
+            };
+
+            let mut changed = false;
+            for part in 0..idx.len() {
+                if moved.contains(&(idx.get_prefix_version(part), part as u32)) {
+                    idx.set_part(part as u32, to);
+                    changed = true;
+                }
+            }
+
+            if changed {
+                rewritten.insert(ver, idx);
+            }
+        }
+
+        // Moved packs, rewritten indexes and the sweep land in one write
+        self.drop_versions_with(batch, &vers, rewritten)
+    }
+
+    /// Removes commits and every record stored under their versions except
+    /// packs still referenced by a kept version's index.
+    fn drop_versions(&self, vers: &[u64]) -> Result<GcStats, Error> {
+        self.drop_versions_with(WriteBatch::new(), vers, HashMap::new())
+    }
+
+    /// `drop_versions` adding to `batch`, with `rewritten` indexes replacing
+    /// the stored ones of their versions. Those are written whole.
+    fn drop_versions_with(&self, mut batch: WriteBatch, vers: &[u64], mut rewritten: HashMap<u64, Index>) -> Result<GcStats, Error> {
+        let mut stats = GcStats {
+            versions: vers.len() as u64,
+            ..Default::default()
         };
 
-        Ok(idx)
+        let mut sweep = vers.to_vec();
+        sweep.extend(self.cstate.retired());
+        if sweep.is_empty() && rewritten.is_empty() {
+            return Ok(stats);
+        }
+
+        let dropped: HashSet<u64> = sweep.iter().cloned().collect();
+        let mut referenced = HashSet::new();
+
+        for ver in self.cstate.versions() {
+            if dropped.contains(&ver) {
+                continue;
+            }
+
+            let (idx, whole) = match rewritten.remove(&ver) {
+                Some(idx) => (idx, true),
+                None => match self.load_index_at(ver)? {
+                    Some(idx) => {
+                        // Deltas resolved through a dropped record are rewritten whole
+                        let whole = idx.chain().iter().skip(1).any(|pver| dropped.contains(pver));
+                        (idx, whole)
+                    },
+                    None => {
+                        // Open version not synced yet, it reads its parent's index
+                        let prev_ver = self.cstate.get_commit(ver).map(|c| c.prev_ver).unwrap_or(0);
+                        if !dropped.contains(&prev_ver) {
+                            continue;
+                        }
+
+                        match self.load_index_at(prev_ver)? {
+                            Some(idx) => (idx, true),
+                            None => continue,
+                        }
+                    },
+                },
+            };
+
+            if whole {
+                batch.put(ver, "index".as_bytes(), &idx.to_vec()?);
+            }
+
+            for part in 0..idx.len() {
+                let pver = idx.get_prefix_version(part);
+                if dropped.contains(&pver) {
+                    referenced.insert((pver, part as u32));
+                }
+            }
+        }
+
+        let mut retired = BTreeSet::new();
+
+        for ver in sweep {
+            for (key, val) in self.kv.scan(ver, &Scan::all())? {
+                if let Ok(part) = key.as_slice().try_into() {
+                    if referenced.contains(&(ver, u32::from_be_bytes(part))) {
+                        retired.insert(ver);
+                        continue;
+                    }
+                }
+
+                stats.records += 1;
+                stats.bytes += (key.len() + val.len()) as u64;
+                batch.delete(ver, &key);
+            }
+        }
+
+        self.cstate.drop_commits(&dropped, retired);
+
+        self.write_with_state(batch)?;
+        self.kv.sync()?;
+
+        debug!("dropped versions {:?}", stats);
+        Ok(stats)
+    }
+
+    fn load_index_at(&self, ver: u64)-> Result<Option<Index>, Error> {
+        debug!("load index at ver: {}", ver);
+        Index::load(&self.kv, ver)
+    }
+
+    fn load_part(&self, ver: u64, part: u32) -> Result<Option<Arc<Pack>>, Error> {
+        if ver == 0 {
+            return Ok(None);
+        }
+
+        let pack = self.packs.load(&self.kv, ver, part)?.ok_or(Error::PackNotFound(ver, part))?;
+        Ok(Some(pack))
+    }
+
+    fn committed_index(&self, ver: u64) -> Result<Index, Error> {
+        if self.cstate.get_commit(ver).is_none() || self.cstate.is_open(ver) {
+            return Err(Error::VersionNotCommitted(ver));
+        }
+
+        self.load_index_at(ver)?.ok_or(Error::IndexNotFound)
+    }
+
+    /// Three-way merge of `theirs` into the writable tree `t`, whose contents
+    /// are "ours". Both must descend from `base`, `Error::NotAncestor`
+    /// otherwise. Keys changed only by theirs are applied, keys changed on
+    /// both sides go through `resolver`. Partitions theirs left at their base
+    /// version are skipped. The result is left in `t` for the caller to
+    /// commit.
+    pub fn merge(&self, t: &Tree, base: u64, theirs: u64, resolver: &mut dyn Resolver) -> Result<MergeStats, Error> {
+        debug!("merge base: {} theirs: {} into: {}", base, theirs, t.commit.ver);
+
+        for ver in [t.commit.prev_ver, theirs].iter() {
+            if self.cstate.common_ancestor(*ver, base) != Some(base) {
+                return Err(Error::NotAncestor(base, *ver));
+            }
+        }
+
+        let base_idx = self.committed_index(base)?;
+        let their_idx = self.committed_index(theirs)?;
+
+        if base_idx.len() != t.idx.len() || their_idx.len() != t.idx.len() {
+            return Err(Error::PartitionMismatch);
+        }
+
+        let mut stats = MergeStats::default();
+        let mut parts = Vec::new();
+
+        for part in 0..t.idx.len() {
+            let bv = base_idx.get_prefix_version(part);
+            let tv = their_idx.get_prefix_version(part);
+            let ov = t.idx.get_prefix_version(part);
+
+            if bv == tv || ov == tv {
+                continue;
+            }
+
+            let part = part as u32;
+            stats.parts += 1;
+
+            let bp = self.load_part(bv, part)?;
+            let op = self.load_part(ov, part)?;
+            let tp = self.load_part(tv, part)?;
+
+            let mut changes = Vec::new();
+            for (key, m) in merge::merge_packs(&bp, &op, &tp) {
+                match m {
+                    KeyMerge::Keep => {},
+                    KeyMerge::Apply(val) => changes.push((key, val)),
+                    KeyMerge::Conflict(c) => {
+                        stats.conflicts += 1;
+                        match resolver.resolve(&c)? {
+                            Resolution::Ours => {},
+                            Resolution::Theirs => {
+                                let e = merge::entry(&tp, &key).cloned();
+                                changes.push((key, e));
+                            },
+                            Resolution::Value(val) => {
+                                changes.push((key, val.map(|val| (t.commit.ver, val))));
+                            },
+                        }
+                    },
+                }
+            }
+
+            stats.applied += changes.len() as u64;
+            parts.push((part, changes));
+        }
+
+        // Nothing is written until every conflict is resolved
+        for (part, changes) in parts {
+            t.apply_part(part, changes)?;
+        }
+
+        debug!("merge done {:?}", stats);
+        Ok(stats)
+    }
+
+    /// Splits the partitions of the writable tree `t` to 2^prefix_bits,
+    /// rehashing its keys into new packs of its version. Older versions
+    /// keep their own index. The result is left in `t` for the caller to
+    /// commit, which records the new count as the store's.
+    pub fn reshard(&self, t: &mut Tree, prefix_bits: usize) -> Result<(), Error> {
+        debug!("reshard ver: {} from: {} to: {}", t.commit.ver, t.idx.prefix_bits(), prefix_bits);
+
+        self.options().prefix_bits(prefix_bits).validate()?;
+        t.reshard(prefix_bits)
     }
 
-    pub fn diff(&self, aver: u16, bver: u16) -> Result<DiffIter, Error> {
+    pub fn diff(&self, aver: u64, bver: u64) -> Result<DiffIter, Error> {
         let a_idx = self.load_index_at(aver)?.ok_or(Error::IndexNotFound)?;
         let b_idx = self.load_index_at(bver)?.ok_or(Error::IndexNotFound)?;
 
-        let d = DiffIter::new(self.kv.clone(), a_idx, b_idx);
+        let d = DiffIter::new(self.kv.clone(), self.packs.clone(), a_idx, b_idx);
 
         Ok(d)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use keyvalue::memory::MemoryKV;
+    use keyvalue::checksum::ChecksumKV;
+    use keyvalue::sqlite::SqliteDB;
+    use crate::index::MAX_DELTA_DEPTH;
+    use crate::merge::{TakeTheirs, NoConflicts};
+
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn memory_store(prefix_bits: usize) -> (Arc<Box<dyn KeyValue>>, VStore) {
+        let kv: Arc<Box<dyn KeyValue>> = Arc::new(MemoryKV::new_box());
+        let v = VStore::create_with(kv.clone(), StoreOptions::default().prefix_bits(prefix_bits)).unwrap();
+        (kv, v)
+    }
+
+    /// Commits `key` set to `val` on `branch`, returning the version.
+    fn put_commit(v: &VStore, branch: &str, key: &str, val: &str) -> u64 {
+        let t = v.writable_branch(branch).unwrap();
+        t.put_str(key, val.as_bytes()).unwrap();
+        let ver = t.commit.ver;
+        v.commit(t).unwrap();
+        ver
+    }
+
+    fn value(v: &VStore, ver: u64, key: &str) -> Option<String> {
+        let t = v.read_only(ver).unwrap();
+        t.get_str(key).unwrap().map(|buf| String::from_utf8(buf).unwrap())
+    }
+
+    /// Every version left holds what the n-th commit of `commits` wrote:
+    /// k1..kn and "last" set to n.
+    fn check_contents(v: &VStore, commits: &BTreeMap<u64, usize>) {
+        for ver in v.cstate.versions() {
+            let n = commits[&ver];
+            assert_eq!(value(v, ver, "last"), Some(n.to_string()), "v={}", ver);
+
+            for i in 1..=commits.len() {
+                let expected = if i <= n { Some(i.to_string()) } else { None };
+                assert_eq!(value(v, ver, &format!("k{}", i)), expected, "v={} k{}", ver, i);
+            }
+        }
+    }
+
+    fn numbered_commits(v: &VStore, count: usize) -> BTreeMap<u64, usize> {
+        let mut commits = BTreeMap::new();
+        for n in 1..=count {
+            let t = v.writable().unwrap();
+            t.put_str(&format!("k{}", n), n.to_string().as_bytes()).unwrap();
+            t.put_str("last", n.to_string().as_bytes()).unwrap();
+            commits.insert(t.commit.ver, n);
+            v.commit(t).unwrap();
+        }
+        commits
+    }
+
+    #[test]
+    fn index_deltas_after_squash_and_gc() {
+        let (_, v) = memory_store(3);
+        let commits = numbered_commits(&v, 2 * MAX_DELTA_DEPTH as usize + 8);
+        let vers: Vec<u64> = commits.keys().cloned().collect();
+
+        // Later indexes are deltas resolved through the dropped ones
+        v.squash(vers[2], vers[20]).unwrap();
+        assert_eq!(v.cstate.versions().len(), vers.len() - 17);
+        check_contents(&v, &commits);
+
+        v.gc(&RetentionPolicy::default().keep_last(6)).unwrap();
+        assert_eq!(v.cstate.versions(), vers[vers.len() - 6..].to_vec());
+        check_contents(&v, &commits);
+
+        // Further commits layer deltas on the rewritten records
+        let t = v.writable().unwrap();
+        t.put_str("extra", b"1").unwrap();
+        let ver = t.commit.ver;
+        v.commit(t).unwrap();
+        assert_eq!(value(&v, ver, "k1"), Some("1".to_owned()));
+    }
+
+    #[test]
+    fn stale_state_write_fails_and_reloads() {
+        let (kv, v1) = memory_store(2);
+        let v2 = VStore::open(kv).unwrap();
+        let ver = put_commit(&v1, MAIN_BRANCH, "a", "1");
+
+        // v2 still expects the commit state from before v1's commit
+        assert!(matches!(v2.write_with_state(WriteBatch::new()), Err(Error::ConcurrentWrite)));
+        assert_eq!(v2.cstate.committed_head(MAIN_BRANCH).unwrap(), ver);
+        v2.write_with_state(WriteBatch::new()).unwrap();
+
+        // Taking the lock catches up before writing
+        let ver = put_commit(&v1, MAIN_BRANCH, "a", "2");
+        v2.create_branch("b", ver).unwrap();
+        assert_eq!(value(&v2, ver, "a"), Some("2".to_owned()));
+    }
+
+    #[test]
+    fn lock_held_while_trees_open() {
+        let (kv, v1) = memory_store(2);
+        let v2 = VStore::open(kv).unwrap();
+        let ver = put_commit(&v1, MAIN_BRANCH, "a", "1");
+        v1.create_branch("b", ver).unwrap();
+
+        let t1 = v1.writable().unwrap();
+        let t2 = v1.writable_branch("b").unwrap();
+        v1.commit(t1).unwrap();
+        assert!(matches!(v2.writable(), Err(Error::Locked(..))));
+
+        drop(t2);
+        assert!(v1.writer_lock().unwrap().is_none());
+        v2.writable().unwrap();
+    }
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn sqlite_connections_conflict() {
+        let path = std::env::temp_dir().join(format!("vstore-{}-conflict.db", std::process::id()));
+        let open = || -> Arc<Box<dyn KeyValue>> { Arc::new(SqliteDB::new_box(&path).unwrap()) };
+
+        // As two processes on one store
+        let v1 = VStore::create_with(open(), StoreOptions::default().prefix_bits(2)).unwrap();
+        let v2 = VStore::open(open()).unwrap();
+
+        let t = v1.writable().unwrap();
+        assert!(matches!(v2.writable(), Err(Error::Locked(..))));
+        t.put_str("a", b"1").unwrap();
+        let ver = t.commit.ver;
+        v1.commit(t).unwrap();
+
+        assert!(matches!(v2.write_with_state(WriteBatch::new()), Err(Error::ConcurrentWrite)));
+        assert_eq!(value(&v2, ver, "a"), Some("1".to_owned()));
+
+        let ver = put_commit(&v2, MAIN_BRANCH, "a", "2");
+        assert!(matches!(v1.write_with_state(WriteBatch::new()), Err(Error::ConcurrentWrite)));
+        assert_eq!(value(&v1, ver, "a"), Some("2".to_owned()));
+
+        drop((v1, v2));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn gc_keeps_reachable_versions() {
+        let (_, v) = memory_store(1);
+        let main: Vec<u64> = (1..=6).map(|n| put_commit(&v, MAIN_BRANCH, &format!("k{}", n), "main")).collect();
+        v.create_branch("b", main[1]).unwrap();
+        let b_head = put_commit(&v, "b", "kb", "b");
+        v.create_tag("t", main[2]).unwrap();
+
+        // An open version, synced but not committed
+        let t = v.writable().unwrap();
+        t.put_str("open", b"1").unwrap();
+        v.sync_tree(&t).unwrap();
+        let open = t.commit.ver;
+        drop(t);
+
+        assert_eq!(v.gc(&RetentionPolicy::default()).unwrap().versions, 0);
+
+        v.gc(&RetentionPolicy::default().keep_last(2)).unwrap();
+        let kept = vec![main[1], main[2], main[4], main[5], b_head, open];
+        let mut vers = v.cstate.versions();
+        vers.sort();
+        assert_eq!(vers, kept);
+
+        for (n, ver) in main.iter().enumerate() {
+            if !kept.contains(ver) {
+                continue;
+            }
+
+            for i in 1..=6 {
+                let expected = if i <= n + 1 { Some("main".to_owned()) } else { None };
+                assert_eq!(value(&v, *ver, &format!("k{}", i)), expected);
+            }
+        }
+
+        assert_eq!(value(&v, b_head, "k2"), Some("main".to_owned()));
+        assert_eq!(value(&v, b_head, "kb"), Some("b".to_owned()));
+        assert_eq!(v.writable().unwrap().get_str("open").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn squash_keeps_versions_outside_range() {
+        let (_, v) = memory_store(1);
+        let main: Vec<u64> = (1..=6).map(|n| put_commit(&v, MAIN_BRANCH, &format!("k{}", n), "main")).collect();
+
+        v.create_branch("b", main[3]).unwrap();
+        assert!(matches!(v.squash(main[1], main[4]), Err(Error::VersionInUse(_))));
+        v.delete_branch("b").unwrap();
+
+        v.squash(main[1], main[4]).unwrap();
+        assert_eq!(v.cstate.versions(), vec![main[0], main[1], main[4], main[5]]);
+        assert_eq!(v.cstate.get_commit(main[4]).unwrap().prev_ver, main[1]);
+
+        // Packs written by the dropped versions now live under main[4]
+        for (n, ver) in [(1, main[0]), (2, main[1]), (5, main[4]), (6, main[5])].iter() {
+            for i in 1..=6 {
+                let expected = if i <= *n { Some("main".to_owned()) } else { None };
+                assert_eq!(value(&v, *ver, &format!("k{}", i)), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn merge_base_must_be_ancestor() {
+        let (_, v) = memory_store(1);
+        let root = put_commit(&v, MAIN_BRANCH, "a", "1");
+        v.create_branch("b", root).unwrap();
+        let ours = put_commit(&v, MAIN_BRANCH, "a", "2");
+        let theirs = put_commit(&v, "b", "b", "1");
+
+        // Neither side descends from the other's head
+        for base in [ours, theirs].iter() {
+            let t = v.writable().unwrap();
+            assert!(matches!(v.merge(&t, *base, theirs, &mut TakeTheirs), Err(Error::NotAncestor(..))));
+            v.abort(t).unwrap();
+        }
+
+        let t = v.writable().unwrap();
+        let stats = v.merge(&t, root, theirs, &mut NoConflicts).unwrap();
+        assert_eq!(stats.conflicts, 0);
+        assert_eq!(t.get_str("a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(t.get_str("b").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn squash_refused_under_open_tree() {
+        let (_, v) = memory_store(1);
+        let main: Vec<u64> = (1..=6).map(|n| put_commit(&v, MAIN_BRANCH, &format!("k{}", n), "main")).collect();
+        v.create_branch("b", main[0]).unwrap();
+
+        // Reads main[2]'s pack of k3 through its in-memory index
+        let t = v.writable().unwrap();
+        assert!(matches!(v.squash(main[1], main[4]), Err(Error::VersionInUse(ver)) if ver == t.commit.ver));
+        t.put_str("k7", b"main").unwrap();
+        assert_eq!(t.get_str("k3").unwrap(), Some(b"main".to_vec()));
+        let open = t.commit.ver;
+        v.commit(t).unwrap();
+
+        // A tree on a line not reading the range does not hold it up
+        let tb = v.writable_branch("b").unwrap();
+        v.squash(main[1], main[4]).unwrap();
+        tb.put_str("kb", b"b").unwrap();
+        v.commit(tb).unwrap();
+
+        for i in 1..=7 {
+            assert_eq!(value(&v, open, &format!("k{}", i)), Some("main".to_owned()));
+        }
+    }
+
+    #[test]
+    #[allow(clippy::arc_with_non_send_sync)]
+    fn checksum_mismatch_names_pack() {
+        let kv: Arc<Box<dyn KeyValue>> = Arc::new(MemoryKV::new_box());
+        let ckv: Arc<Box<dyn KeyValue>> = Arc::new(ChecksumKV::create_box(kv.clone()).unwrap());
+        let v = VStore::create_with(ckv.clone(), StoreOptions::default().prefix_bits(2)).unwrap();
+        let ver = put_commit(&v, MAIN_BRANCH, "a", "one");
+        let (_, part, _) = v.read_only(ver).unwrap().idx.get_part(b"a");
+
+        let pkey = part.to_be_bytes();
+        let mut buf = kv.get(ver, &pkey).unwrap().unwrap();
+        buf[0] ^= 1;
+        kv.put(ver, &pkey, &buf).unwrap();
+
+        // Opened again so the pack is not served from the cache
+        let v = VStore::open(ckv).unwrap();
+        let t = v.read_only(ver).unwrap();
+        assert!(matches!(t.get_str("a"), Err(Error::PackCorrupted(pv, p)) if pv == ver && p == part));
+    }
+}